@@ -0,0 +1,55 @@
+-- Settings admins can change per guild. NULL means the bot's default.
+CREATE TABLE guild_settings (
+    guild_id INTEGER PRIMARY KEY,
+    default_voicepack TEXT,
+    timeout_mins INTEGER,
+    volume REAL,
+    -- JSON array of role IDs allowed to use /track. Empty means everyone.
+    allowed_roles TEXT NOT NULL DEFAULT '[]'
+);
+
+-- Short names for characters, usable in place of the character's name in /track
+CREATE TABLE character_aliases (
+    guild_id INTEGER NOT NULL,
+    alias TEXT NOT NULL,
+    character_name TEXT NOT NULL,
+    PRIMARY KEY (guild_id, alias)
+);
+
+-- One row per finished tracking session
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    character_id INTEGER NOT NULL,
+    character_name TEXT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    voicepack TEXT NOT NULL,
+    started TEXT NOT NULL,
+    ended TEXT NOT NULL,
+    kills INTEGER NOT NULL DEFAULT 0,
+    headshot_kills INTEGER NOT NULL DEFAULT 0,
+    deaths INTEGER NOT NULL DEFAULT 0,
+    best_spree INTEGER NOT NULL DEFAULT 0,
+    pentas INTEGER NOT NULL DEFAULT 0,
+    vehicles_destroyed INTEGER NOT NULL DEFAULT 0,
+    revives_given INTEGER NOT NULL DEFAULT 0,
+    revives_received INTEGER NOT NULL DEFAULT 0,
+    unlocks INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX sessions_by_character ON sessions (character_id);
+CREATE INDEX sessions_by_guild ON sessions (guild_id, ended);
+
+-- How many sprees in a session peaked at each multikill tier
+CREATE TABLE session_multikills (
+    session_id INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    tier TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (session_id, tier)
+);
+
+-- How many times each category was triggered in a session
+CREATE TABLE session_categories (
+    session_id INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    category TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (session_id, category)
+);
@@ -0,0 +1,17 @@
+-- The text channel the kill feed is posted in when /track doesn't choose one. NULL means no feed by default.
+ALTER TABLE guild_settings ADD COLUMN feed_channel INTEGER;
+
+-- Categories a guild never wants played
+CREATE TABLE guild_muted_categories (
+    guild_id INTEGER NOT NULL,
+    category TEXT NOT NULL,
+    PRIMARY KEY (guild_id, category)
+);
+
+-- A guild's cooldowns, on top of (and replacing) the voicepack's
+CREATE TABLE guild_cooldowns (
+    guild_id INTEGER NOT NULL,
+    category TEXT NOT NULL,
+    secs INTEGER NOT NULL,
+    PRIMARY KEY (guild_id, category)
+);
@@ -0,0 +1,7 @@
+-- The characters a Discord user has linked with /link, so /track can be used without a character name
+CREATE TABLE linked_characters (
+    user_id INTEGER NOT NULL,
+    character_id INTEGER NOT NULL,
+    character_name TEXT NOT NULL,
+    PRIMARY KEY (user_id, character_id)
+);
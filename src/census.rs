@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::env;
+
+use auraxis::api::client::{ApiClient, ApiClientConfig};
+use auraxis::api::request::FilterType;
+use auraxis::api::CensusCollection;
+use chrono::{DateTime, TimeZone, Utc};
+
+// The kinds of vehicles rules can distinguish between
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VehicleKind {
+    Air,
+    Galaxy,
+    Sunderer,
+    Mbt,
+    Colossus,
+    Bastion,
+    Other,
+}
+
+impl VehicleKind {
+    fn from_census(name: &str, type_name: &str) -> VehicleKind {
+        match name {
+            "Galaxy" => VehicleKind::Galaxy,
+            "Sunderer" => VehicleKind::Sunderer,
+            "Magrider" | "Vanguard" | "Prowler" | "Chimera" => VehicleKind::Mbt,
+            "Colossus" => VehicleKind::Colossus,
+            "Scythe" | "Reaver" | "Mosquito" | "Liberator" | "Valkyrie" | "Dervish" => {
+                VehicleKind::Air
+            }
+            _ if name.contains("Bastion") => VehicleKind::Bastion,
+            _ if type_name == "Fighter" => VehicleKind::Air,
+            _ => VehicleKind::Other,
+        }
+    }
+
+    // The `vehicle_type` rules match on
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VehicleKind::Air => "air",
+            VehicleKind::Galaxy => "galaxy",
+            VehicleKind::Sunderer => "sunderer",
+            VehicleKind::Mbt => "mbt",
+            VehicleKind::Colossus => "colossus",
+            VehicleKind::Bastion => "bastion",
+            VehicleKind::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Vehicle {
+    pub name: String,
+    pub kind: VehicleKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct Character {
+    pub character_id: u64,
+    pub name: String,
+    pub faction_id: u64,
+    pub world_id: u64,
+    pub battle_rank: u64,
+    pub prestige_level: u64,
+    pub last_login: Option<DateTime<Utc>>,
+}
+
+impl Character {
+    pub fn faction_name(&self) -> &'static str {
+        match self.faction_id {
+            1 => "Vanu Sovereignty",
+            2 => "New Conglomerate",
+            3 => "Terran Republic",
+            4 => "Nanite Systems Operatives",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn faction_color(&self) -> u32 {
+        match self.faction_id {
+            1 => 0x8E44AD,
+            2 => 0x2E86DE,
+            3 => 0xC0392B,
+            _ => 0x95A5A6,
+        }
+    }
+
+    pub fn world_name(&self) -> &'static str {
+        match self.world_id {
+            1 => "Connery",
+            10 => "Miller",
+            13 => "Cobalt",
+            17 => "Emerald",
+            19 => "Jaeger",
+            40 => "SolTech",
+            1000 => "Genudine",
+            2000 => "Ceres",
+            _ => "Unknown",
+        }
+    }
+}
+
+fn census_client() -> ApiClient {
+    let sid = env::var("SERVICE_ID").expect("Expected a service ID in the environment");
+    let mut client_config = ApiClientConfig::default();
+    client_config.service_id = Some(sid);
+
+    ApiClient::new(client_config)
+}
+
+fn parse_u64(val: &serde_json::Value, field: &str) -> Option<u64> {
+    val.get(field)
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+// Looks up a character by name, along with the faction and world we need to follow world events for them
+pub async fn get_character(character_name: &str) -> Result<Character, String> {
+    fetch_character("name.first_lower", &character_name.to_lowercase()).await
+}
+
+pub async fn get_character_by_id(character_id: u64) -> Result<Character, String> {
+    fetch_character("character_id", &character_id.to_string()).await
+}
+
+async fn fetch_character(filter_field: &str, filter_value: &str) -> Result<Character, String> {
+    let client = census_client();
+
+    let query = client
+        .get(CensusCollection::Character)
+        .filter(filter_field, FilterType::EqualTo, filter_value)
+        .limit(1)
+        .show("character_id")
+        .show("name")
+        .show("faction_id")
+        .show("battle_rank")
+        .show("prestige_level")
+        .show("times")
+        .build();
+
+    let response = query
+        .await
+        .map_err(|err| format!("Could not query the Census: {:?}", err))?;
+    let item = response
+        .items
+        .first()
+        .ok_or("Could not find a character with that name.")?;
+    let character_id = parse_u64(item, "character_id")
+        .ok_or("Could not get character ID from Census response.")?;
+    let name = item
+        .get("name")
+        .and_then(|n| n.get("first"))
+        .and_then(|n| n.as_str())
+        .unwrap_or(filter_value)
+        .to_string();
+    let faction_id = parse_u64(item, "faction_id").unwrap_or_default();
+    let battle_rank = item
+        .get("battle_rank")
+        .and_then(|br| parse_u64(br, "value"))
+        .unwrap_or_default();
+    let prestige_level = parse_u64(item, "prestige_level").unwrap_or_default();
+    let last_login = item
+        .get("times")
+        .and_then(|times| parse_u64(times, "last_login"))
+        .and_then(|secs| Utc.timestamp_opt(secs as i64, 0).single());
+
+    let query = client
+        .get("characters_world")
+        .filter(
+            "character_id",
+            FilterType::EqualTo,
+            character_id.to_string().as_str(),
+        )
+        .limit(1)
+        .show("world_id")
+        .build();
+
+    let world_id = match query.await {
+        Ok(response) => response
+            .items
+            .first()
+            .and_then(|v| parse_u64(v, "world_id"))
+            .ok_or("Could not get world ID from Census response.")?,
+        Err(err) => return Err(format!("Could not query the Census: {:?}", err)),
+    };
+
+    Ok(Character {
+        character_id,
+        name,
+        faction_id,
+        world_id,
+        battle_rank,
+        prestige_level,
+        last_login,
+    })
+}
+
+// The character's lifetime stats (e.g. "kills", "deaths", "time"), by stat name
+pub async fn get_lifetime_stats(character_id: u64) -> Result<HashMap<String, u64>, String> {
+    let query = census_client()
+        .get("characters_stat_history")
+        .filter(
+            "character_id",
+            FilterType::EqualTo,
+            character_id.to_string().as_str(),
+        )
+        .limit(50)
+        .show("stat_name")
+        .show("all_time")
+        .build();
+
+    let response = query
+        .await
+        .map_err(|err| format!("Could not query the Census: {:?}", err))?;
+    Ok(response
+        .items
+        .iter()
+        .filter_map(|item| {
+            let stat_name = item.get("stat_name").and_then(|v| v.as_str())?;
+            let all_time = parse_u64(item, "all_time")?;
+            Some((stat_name.to_string(), all_time))
+        })
+        .collect())
+}
+
+// Which of the characters are online right now
+pub async fn get_online_characters(character_ids: &[u64]) -> Result<Vec<u64>, String> {
+    let ids: Vec<String> = character_ids.iter().map(|id| id.to_string()).collect();
+    let query = census_client()
+        .get("characters_online_status")
+        .filter("character_id", FilterType::EqualTo, ids.join(",").as_str())
+        .limit(100)
+        .build();
+
+    let response = query
+        .await
+        .map_err(|err| format!("Could not query the Census: {:?}", err))?;
+    // `online_status` is the ID of the world they're on, or 0 if they're offline
+    Ok(response
+        .items
+        .iter()
+        .filter(|item| parse_u64(item, "online_status").map_or(false, |status| status > 0))
+        .filter_map(|item| parse_u64(item, "character_id"))
+        .collect())
+}
+
+// When the character most recently finished an achievement (e.g. a medal or ribbon) after `since`, if they have
+pub async fn get_latest_achievement(
+    character_id: u64,
+    since: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    latest_unlock("characters_achievement", "finish", character_id, since).await
+}
+
+// When the character most recently unlocked a skill (a certification, e.g. a vehicle upgrade) after `since`, if they
+// have
+pub async fn get_latest_skill(
+    character_id: u64,
+    since: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    latest_unlock("characters_skill", "last_save", character_id, since).await
+}
+
+// The latest of the character's entries in `collection` with a `time_field` (in Unix seconds) after `since`
+async fn latest_unlock(
+    collection: &str,
+    time_field: &str,
+    character_id: u64,
+    since: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let query = census_client()
+        .get(collection)
+        .filter(
+            "character_id",
+            FilterType::EqualTo,
+            character_id.to_string().as_str(),
+        )
+        .filter(
+            time_field,
+            FilterType::GreaterThan,
+            since.timestamp().to_string().as_str(),
+        )
+        .limit(100)
+        .show(time_field)
+        .build();
+
+    let response = query
+        .await
+        .map_err(|err| format!("Could not query the Census: {:?}", err))?;
+    Ok(response
+        .items
+        .iter()
+        .filter_map(|item| parse_u64(item, time_field))
+        .max()
+        .and_then(|secs| Utc.timestamp_opt(secs as i64, 0).single()))
+}
+
+// Builds a client pointed at Sanctuary Census, which has more complete static data than the official Census
+fn sanctuary_client() -> ApiClient {
+    let sid = env::var("SERVICE_ID").expect("Expected a service ID in the environment");
+    let mut client_config = ApiClientConfig::default();
+    client_config.service_id = Some(sid);
+    client_config.api_url = Some(String::from("https://census.lithafalcon.cc"));
+    client_config.environment = Some(String::from("ps2"));
+
+    ApiClient::new(client_config)
+}
+
+#[derive(Clone, Debug)]
+pub struct Weapon {
+    pub name: String,
+    // The item category's name, e.g. "Knife" or "Grenade"
+    pub category: String,
+}
+
+// Maps item category IDs to their names
+async fn get_item_categories() -> HashMap<u64, String> {
+    let query = sanctuary_client()
+        .get("item_category")
+        .limit(1000)
+        .show("item_category_id")
+        .show("name")
+        .build();
+
+    match query.await {
+        Ok(response) => response
+            .items
+            .iter()
+            .filter_map(|val| {
+                let item_category_id = val
+                    .get("item_category_id")
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse::<u64>().ok())?;
+                let name = val
+                    .get("name")
+                    .and_then(|n| n.get("en"))
+                    .and_then(|n| n.as_str())?;
+
+                Some((item_category_id, name.to_string()))
+            })
+            .collect(),
+        Err(err) => panic!(
+            "Could not query Sanctuary Census for item categories: {}",
+            err
+        ),
+    }
+}
+
+// const WEAPON_URL: &str = "https://census.lithafalcon.cc/get/ps2/item?code_factory_name=Weapon&c:show=item_id,item_category_id,name&c:limit=5000";
+pub async fn get_weapons() -> HashMap<u64, Weapon> {
+    let item_categories = get_item_categories().await;
+
+    let query = sanctuary_client()
+        .get("item")
+        .limit(5000)
+        .show("item_id")
+        .show("item_category_id")
+        .show("name")
+        .filter("code_factory_name", FilterType::EqualTo, "Weapon")
+        .build();
+
+    match query.await {
+        Ok(response) => response
+            .items
+            .iter()
+            .filter_map(|val| {
+                let item_id = val
+                    .get("item_id")
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse::<u64>().ok())?;
+                let name = val
+                    .get("name")
+                    .and_then(|n| n.get("en"))
+                    .and_then(|n| n.as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                let category = val
+                    .get("item_category_id")
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .and_then(|id| item_categories.get(&id))
+                    .cloned()
+                    .unwrap_or_default();
+
+                Some((item_id, Weapon { name, category }))
+            })
+            .collect(),
+        Err(err) => panic!("Could not query Sanctuary Census for weapons: {}", err),
+    }
+}
+
+pub async fn get_vehicles() -> HashMap<u64, Vehicle> {
+    let query = sanctuary_client()
+        .get("vehicle")
+        .limit(500)
+        .show("vehicle_id")
+        .show("name")
+        .show("type_name")
+        .build();
+
+    match query.await {
+        Ok(response) => response
+            .items
+            .iter()
+            .filter_map(|val| {
+                let vehicle_id = val
+                    .get("vehicle_id")
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse::<u64>().ok())?;
+                let name = val
+                    .get("name")
+                    .and_then(|n| n.get("en"))
+                    .and_then(|n| n.as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                let type_name = val
+                    .get("type_name")
+                    .and_then(|t| t.as_str())
+                    .unwrap_or_default();
+                let kind = VehicleKind::from_census(&name, type_name);
+
+                Some((vehicle_id, Vehicle { name, kind }))
+            })
+            .collect(),
+        Err(err) => panic!("Could not query Sanctuary Census for vehicles: {}", err),
+    }
+}
+
+// Maps facility IDs to their facility type, e.g. "Bio Lab" or "Amp Station"
+pub async fn get_facility_types() -> HashMap<u64, String> {
+    let query = sanctuary_client()
+        .get("map_region")
+        .limit(5000)
+        .show("facility_id")
+        .show("facility_type")
+        .build();
+
+    match query.await {
+        Ok(response) => response
+            .items
+            .iter()
+            .filter_map(|val| {
+                let facility_id = val
+                    .get("facility_id")
+                    .and_then(|v| v.as_str())
+                    .and_then(|v| v.parse::<u64>().ok())?;
+                let facility_type = val.get("facility_type").and_then(|t| t.as_str())?;
+
+                Some((facility_id, facility_type.to_string()))
+            })
+            .collect(),
+        Err(err) => panic!(
+            "Could not query Sanctuary Census for facility types: {}",
+            err
+        ),
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use plotters::coord::Shift;
+use plotters::prelude::*;
+
+use crate::stats::SessionStats;
+
+const WIDTH: u32 = 1200;
+const HEIGHT: u32 = 500;
+// Weapons past this many are lumped into "Other"
+const MAX_WEAPON_SLICES: usize = 6;
+const KILLS_COLOR: RGBColor = RGBColor(39, 174, 96);
+const DEATHS_COLOR: RGBColor = RGBColor(192, 57, 43);
+const SPREE_COLOR: RGBColor = RGBColor(41, 128, 185);
+
+// Renders a PNG of the session to `path`: kills and deaths over time with spree peaks marked, next to a pie of kills
+// by weapon
+pub fn render_session(
+    stats: &SessionStats,
+    ended: DateTime<Utc>,
+    path: &Path,
+) -> Result<(), String> {
+    let root = BitMapBackend::new(path, (WIDTH, HEIGHT)).into_drawing_area();
+    root.fill(&WHITE).map_err(|why| why.to_string())?;
+
+    let (timeline_area, weapons_area) = root.split_horizontally(WIDTH * 2 / 3);
+    draw_timeline(stats, ended, &timeline_area)?;
+    draw_weapons(stats, &weapons_area)?;
+
+    root.present().map_err(|why| why.to_string())
+}
+
+fn draw_timeline<DB: DrawingBackend>(
+    stats: &SessionStats,
+    ended: DateTime<Utc>,
+    area: &DrawingArea<DB, Shift>,
+) -> Result<(), String> {
+    let minutes = |time: &DateTime<Utc>| (*time - stats.started).num_seconds().max(0) as f64 / 60.0;
+    let end = minutes(&ended).max(1.0);
+    // Running totals, as a line from the start of the session to the end
+    let running_total = |times: &[DateTime<Utc>]| {
+        let mut points = vec![(0.0, 0)];
+        for (index, time) in times.iter().enumerate() {
+            points.push((minutes(time), index as u32 + 1));
+        }
+        points.push((end, times.len() as u32));
+        points
+    };
+    let max_total = stats.kill_times.len().max(stats.death_times.len()) as u32;
+
+    let mut chart = ChartBuilder::on(area)
+        .caption("Kills and deaths", ("sans-serif", 24))
+        .margin(15)
+        .x_label_area_size(35)
+        .y_label_area_size(40)
+        .build_cartesian_2d(0.0..end, 0..max_total + 1)
+        .map_err(|why| why.to_string())?;
+    chart
+        .configure_mesh()
+        .x_desc("Minutes")
+        .y_desc("Total")
+        .draw()
+        .map_err(|why| why.to_string())?;
+
+    chart
+        .draw_series(LineSeries::new(
+            running_total(&stats.kill_times),
+            KILLS_COLOR.stroke_width(2),
+        ))
+        .map_err(|why| why.to_string())?
+        .label("Kills")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], KILLS_COLOR));
+    chart
+        .draw_series(LineSeries::new(
+            running_total(&stats.death_times),
+            DEATHS_COLOR.stroke_width(2),
+        ))
+        .map_err(|why| why.to_string())?
+        .label("Deaths")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], DEATHS_COLOR));
+
+    // Spree peaks sit on the kills line, labelled with the spree's kill count
+    chart
+        .draw_series(stats.spree_peaks.iter().map(|(time, kills)| {
+            let total = stats.kill_times.iter().filter(|kill| *kill <= time).count() as u32;
+            EmptyElement::at((minutes(time), total))
+                + Circle::new((0, 0), 5, SPREE_COLOR.filled())
+                + Text::new(format!("x{}", kills), (6, -18), ("sans-serif", 16))
+        }))
+        .map_err(|why| why.to_string())?
+        .label("Multikills")
+        .legend(|(x, y)| Circle::new((x + 10, y), 5, SPREE_COLOR.filled()));
+
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::UpperLeft)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .map_err(|why| why.to_string())
+}
+
+fn draw_weapons<DB: DrawingBackend>(
+    stats: &SessionStats,
+    area: &DrawingArea<DB, Shift>,
+) -> Result<(), String> {
+    let area = area
+        .titled("Kills by weapon", ("sans-serif", 24))
+        .map_err(|why| why.to_string())?;
+
+    let mut weapons: Vec<(&String, &u32)> = stats.kills_by_weapon.iter().collect();
+    weapons.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    if weapons.is_empty() {
+        let (width, height) = area.dim_in_pixel();
+        return area
+            .draw(&Text::new(
+                "No kills",
+                (width as i32 / 2 - 30, height as i32 / 2),
+                ("sans-serif", 20),
+            ))
+            .map_err(|why| why.to_string());
+    }
+
+    let mut slices: Vec<(String, u32)> = weapons
+        .iter()
+        .take(MAX_WEAPON_SLICES)
+        .map(|(name, kills)| (name.to_string(), **kills))
+        .collect();
+    if weapons.len() > MAX_WEAPON_SLICES {
+        slices.pop();
+        let other = weapons
+            .iter()
+            .skip(MAX_WEAPON_SLICES - 1)
+            .map(|(_, kills)| **kills)
+            .sum();
+        slices.push(("Other".to_string(), other));
+    }
+
+    let sizes: Vec<f64> = slices.iter().map(|(_, kills)| *kills as f64).collect();
+    let labels: Vec<String> = slices
+        .iter()
+        .map(|(name, kills)| format!("{} ({})", name, kills))
+        .collect();
+    let colors: Vec<RGBColor> = (0..slices.len())
+        .map(|index| {
+            let (r, g, b) = Palette99::COLORS[index % Palette99::COLORS.len()];
+            RGBColor(r, g, b)
+        })
+        .collect();
+
+    let (width, height) = area.dim_in_pixel();
+    let center = (width as i32 / 2, height as i32 / 2);
+    // Leave room around the pie for the labels
+    let radius = (width.min(height) as f64 / 2.0 - 70.0).max(20.0);
+    let mut pie = Pie::new(&center, &radius, &sizes, &colors, &labels);
+    pie.label_style(("sans-serif", 14).into_font().color(&BLACK));
+
+    area.draw(&pie).map_err(|why| why.to_string())
+}
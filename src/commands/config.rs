@@ -0,0 +1,435 @@
+use serenity::builder::{CreateApplicationCommand, CreateEmbed};
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::model::prelude::{ChannelType, GuildId, Permissions};
+use serenity::prelude::Context;
+
+use crate::commands::track::add_voicepack_choices;
+use crate::commands::volume;
+use crate::repo::{GuildSettings, Repo};
+use crate::rules::Manifest;
+use crate::{CommandResponse, Database};
+
+const MAX_TIMEOUT_MINS: i64 = 120;
+const MAX_COOLDOWN_SECS: i64 = 3600;
+
+pub async fn run(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+    options: &[CommandDataOption],
+) -> CommandResponse {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => return CommandResponse::Message("Command only available in guilds.".to_string()),
+    };
+
+    // Discord hides the command from everyone else, but check anyway in case a server overrides that
+    let is_admin = interaction
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .map_or(false, |permissions| permissions.manage_guild());
+    if !is_admin {
+        return CommandResponse::Message(
+            "You need the Manage Server permission to change the bot's settings.".to_string(),
+        );
+    }
+
+    let subcommand = match options.first() {
+        Some(subcommand) => subcommand,
+        None => return CommandResponse::Message("Please choose a setting.".to_string()),
+    };
+    let option = |option_name: &str| {
+        subcommand
+            .options
+            .iter()
+            .find(|option| option.name == option_name)
+            .and_then(|option| option.value.as_ref())
+    };
+
+    let repo = ctx
+        .data
+        .read()
+        .await
+        .get::<Database>()
+        .cloned()
+        .expect("Unable to get the database in /config");
+    let mut settings = match repo.guild_settings(guild_id.0) {
+        Ok(settings) => settings,
+        Err(why) => {
+            println!("Could not load guild settings in /config: {}", why);
+            return CommandResponse::Message("Could not load this server's settings.".to_string());
+        }
+    };
+
+    let message = match subcommand.name.as_str() {
+        "show" => return CommandResponse::Embed(describe(&settings)),
+        "voicepack" => {
+            settings.default_voicepack = option("voicepack")
+                .and_then(|voicepack| voicepack.as_str())
+                .map(|voicepack| voicepack.to_string());
+            match &settings.default_voicepack {
+                Some(voicepack) => {
+                    // Better to find a broken manifest now than when someone tries to /track
+                    if let Err(why) = Manifest::load(voicepack, guild_id.0) {
+                        return CommandResponse::Message(format!(
+                            "Could not load voicepack '{}': {}",
+                            voicepack, why
+                        ));
+                    }
+                    format!(
+                        "/track will use '{}' when no voicepack is chosen.",
+                        voicepack
+                    )
+                }
+                None => "/track will need a voicepack to be chosen.".to_string(),
+            }
+        }
+        "timeout" => {
+            settings.timeout_mins = option("minutes")
+                .and_then(|minutes| minutes.as_i64())
+                .map(|minutes| minutes.clamp(1, MAX_TIMEOUT_MINS) as u32);
+            match settings.timeout_mins {
+                Some(minutes) => format!(
+                    "Sessions will end after {} minutes without any events.",
+                    minutes
+                ),
+                None => "Sessions will end after the default timeout.".to_string(),
+            }
+        }
+        "volume" => {
+            let percent = option("percent")
+                .and_then(|percent| percent.as_i64())
+                .unwrap_or(100)
+                .clamp(0, volume::MAX_PERCENT);
+            let volume = percent as f32 / 100.0;
+            settings.volume = Some(volume);
+            volume::apply(ctx, guild_id, volume).await;
+            format!("Set the volume to {}%.", percent)
+        }
+        "feed_channel" => {
+            settings.feed_channel = option("channel")
+                .and_then(|channel| channel.as_str())
+                .and_then(|channel| channel.parse().ok());
+            match settings.feed_channel {
+                Some(channel_id) => format!(
+                    "Sessions will post a kill feed in <#{}> unless /track says otherwise.",
+                    channel_id
+                ),
+                None => "Sessions won't post a kill feed unless /track asks for one.".to_string(),
+            }
+        }
+        "mute" | "unmute" => {
+            let category = match option("category").and_then(|category| category.as_str()) {
+                Some(category) => category.trim().to_lowercase(),
+                None => return CommandResponse::Message("Please provide a category.".to_string()),
+            };
+            settings.muted_categories.retain(|muted| *muted != category);
+            if subcommand.name == "mute" {
+                settings.muted_categories.push(category.clone());
+                settings.muted_categories.sort();
+                format!("Muted '{}'.", category)
+            } else {
+                format!("Unmuted '{}'.", category)
+            }
+        }
+        "cooldown" => {
+            let category = match option("category").and_then(|category| category.as_str()) {
+                Some(category) => category.trim().to_lowercase(),
+                None => return CommandResponse::Message("Please provide a category.".to_string()),
+            };
+            match option("seconds").and_then(|seconds| seconds.as_i64()) {
+                Some(seconds) => {
+                    let seconds = seconds.clamp(0, MAX_COOLDOWN_SECS) as u64;
+                    settings.cooldowns.insert(category.clone(), seconds);
+                    format!(
+                        "'{}' won't play more than once every {} seconds.",
+                        category, seconds
+                    )
+                }
+                None => {
+                    settings.cooldowns.remove(&category);
+                    format!("'{}' is back to the voicepack's cooldown.", category)
+                }
+            }
+        }
+        "allowed_role" => {
+            let role_id = match option("role")
+                .and_then(|role| role.as_str())
+                .and_then(|role| role.parse::<u64>().ok())
+            {
+                Some(role_id) => role_id,
+                None => return CommandResponse::Message("Please provide a role.".to_string()),
+            };
+            let allowed = option("allowed")
+                .and_then(|allowed| allowed.as_bool())
+                .unwrap_or(true);
+            settings.allowed_roles.retain(|role| *role != role_id);
+            if allowed {
+                settings.allowed_roles.push(role_id);
+                format!("<@&{}> can now start tracking sessions.", role_id)
+            } else if settings.allowed_roles.is_empty() {
+                "Anyone can start tracking sessions now.".to_string()
+            } else {
+                format!("<@&{}> can no longer start tracking sessions.", role_id)
+            }
+        }
+        "alias" => return set_alias(&repo, guild_id, option),
+        _ => return CommandResponse::Message("not implemented :(".to_string()),
+    };
+
+    if let Err(why) = repo.save_guild_settings(&settings) {
+        println!("Could not save guild settings in /config: {}", why);
+        return CommandResponse::Message("Could not save this server's settings.".to_string());
+    }
+    CommandResponse::Message(message)
+}
+
+fn set_alias<'a>(
+    repo: &Repo,
+    guild_id: GuildId,
+    option: impl Fn(&str) -> Option<&'a serde_json::Value>,
+) -> CommandResponse {
+    let alias = match option("alias").and_then(|alias| alias.as_str()) {
+        Some(alias) => alias.trim(),
+        None => return CommandResponse::Message("Please provide an alias.".to_string()),
+    };
+
+    let result = match option("character_name").and_then(|name| name.as_str()) {
+        Some(character_name) => repo
+            .set_alias(guild_id.0, alias, character_name.trim())
+            .map(|_| format!("'{}' now stands for {}.", alias, character_name.trim())),
+        None => repo.remove_alias(guild_id.0, alias).map(|removed| {
+            if removed {
+                format!("Removed the alias '{}'.", alias)
+            } else {
+                format!("There's no alias '{}'.", alias)
+            }
+        }),
+    };
+    match result {
+        Ok(message) => CommandResponse::Message(message),
+        Err(why) => {
+            println!("Could not save alias in /config: {}", why);
+            CommandResponse::Message("Could not save the alias.".to_string())
+        }
+    }
+}
+
+fn describe(settings: &GuildSettings) -> CreateEmbed {
+    let or_default = |value: Option<String>| value.unwrap_or_else(|| "Default".to_string());
+    let list = |values: Vec<String>| {
+        if values.is_empty() {
+            "None".to_string()
+        } else {
+            values.join(", ")
+        }
+    };
+    let mut cooldowns: Vec<String> = settings
+        .cooldowns
+        .iter()
+        .map(|(category, seconds)| format!("{} ({}s)", category, seconds))
+        .collect();
+    cooldowns.sort();
+
+    let mut embed = CreateEmbed::default();
+    embed
+        .title("Server settings")
+        .field(
+            "Default Voicepack",
+            or_default(settings.default_voicepack.clone()),
+            true,
+        )
+        .field(
+            "Idle Timeout",
+            or_default(
+                settings
+                    .timeout_mins
+                    .map(|minutes| format!("{} minutes", minutes)),
+            ),
+            true,
+        )
+        .field(
+            "Volume",
+            or_default(
+                settings
+                    .volume
+                    .map(|volume| format!("{}%", (volume * 100.0).round())),
+            ),
+            true,
+        )
+        .field(
+            "Kill Feed",
+            settings
+                .feed_channel
+                .map_or("Off".to_string(), |channel_id| format!("<#{}>", channel_id)),
+            true,
+        )
+        .field(
+            "Allowed Roles",
+            if settings.allowed_roles.is_empty() {
+                "Everyone".to_string()
+            } else {
+                list(
+                    settings
+                        .allowed_roles
+                        .iter()
+                        .map(|role_id| format!("<@&{}>", role_id))
+                        .collect(),
+                )
+            },
+            true,
+        )
+        .field("Muted", list(settings.muted_categories.clone()), false)
+        .field("Cooldowns", list(cooldowns), false);
+    embed
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("config")
+        .description("Change the bot's settings for this server")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .create_option(|sub| {
+            sub.name("show")
+                .description("Show this server's settings")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|sub| {
+            sub.name("voicepack")
+                .description("Set the voicepack /track uses when none is chosen")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("voicepack")
+                        .description("The default voicepack (leave empty to require choosing one)")
+                        .kind(CommandOptionType::String)
+                        .required(false);
+                    add_voicepack_choices(c)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("timeout")
+                .description("Set how long a session waits without events before it ends")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("minutes")
+                        .description("The idle timeout in minutes (leave empty for the default)")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .max_int_value(MAX_TIMEOUT_MINS)
+                        .required(false)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("volume")
+                .description("Set how loud the bot plays in this server")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("percent")
+                        .description("The volume, from 0 to 200%")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(0)
+                        .max_int_value(volume::MAX_PERCENT)
+                        .required(true)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("feed_channel")
+                .description("Set the channel sessions post their kill feed in by default")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("channel")
+                        .description(
+                            "The kill feed channel (leave empty to turn the feed off by default)",
+                        )
+                        .kind(CommandOptionType::Channel)
+                        .channel_types(&[ChannelType::Text])
+                        .required(false)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("mute")
+                .description("Never play a category in this server")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("category")
+                        .description("The category, e.g. get_revived")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("unmute")
+                .description("Play a muted category again")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("category")
+                        .description("The category, e.g. get_revived")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("cooldown")
+                .description("Set the minimum time between two plays of a category")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("category")
+                        .description("The category, e.g. heal")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_sub_option(|c| {
+                    c.name("seconds")
+                        .description("The cooldown in seconds (leave empty for the voicepack's)")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(0)
+                        .max_int_value(MAX_COOLDOWN_SECS)
+                        .required(false)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("allowed_role")
+                .description(
+                    "Choose which roles can start tracking sessions (everyone, if none are chosen)",
+                )
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    c.name("role")
+                        .description("The role")
+                        .kind(CommandOptionType::Role)
+                        .required(true)
+                })
+                .create_sub_option(|c| {
+                    c.name("allowed")
+                        .description("Whether the role can start sessions (true if not given)")
+                        .kind(CommandOptionType::Boolean)
+                        .required(false)
+                })
+        })
+        .create_option(|sub| {
+            sub.name("alias")
+                .description("Give a character a short name to use with /track")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|c| {
+                    // /track's character_name needs at least 3 characters, so a shorter alias couldn't be used
+                    c.name("alias")
+                        .description("The short name")
+                        .kind(CommandOptionType::String)
+                        .min_length(3)
+                        .required(true)
+                })
+                .create_sub_option(|c| {
+                    c.name("character_name")
+                        .description(
+                            "The character it stands for (leave empty to remove the alias)",
+                        )
+                        .kind(CommandOptionType::String)
+                        .min_length(3)
+                        .required(false)
+                })
+        })
+}
@@ -0,0 +1,148 @@
+use chrono::{Duration, Utc};
+use serenity::builder::{CreateApplicationCommand, CreateEmbed};
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::prelude::Context;
+
+use crate::repo::Standing;
+use crate::{CommandResponse, Database};
+
+const LEADERBOARD_SIZE: usize = 10;
+
+pub async fn run(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+    options: &[CommandDataOption],
+) -> CommandResponse {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => return CommandResponse::Message("Command only available in guilds.".to_string()),
+    };
+
+    let option = |option_name: &str| {
+        options
+            .iter()
+            .find(|option| option.name == option_name)
+            .and_then(|option| option.value.as_ref())
+            .and_then(|value| value.as_str())
+    };
+    let stat = option("stat").unwrap_or("kills");
+    let window = option("window").unwrap_or("weekly");
+
+    let since = match window {
+        "daily" => Some(Utc::now() - Duration::days(1)),
+        "weekly" => Some(Utc::now() - Duration::weeks(1)),
+        _ => None,
+    };
+    let repo = ctx
+        .data
+        .read()
+        .await
+        .get::<Database>()
+        .cloned()
+        .expect("Unable to get the database in /leaderboard");
+    let standings = match repo.standings(guild_id.0, since) {
+        Ok(standings) => standings,
+        Err(why) => {
+            println!("Could not load standings for /leaderboard: {}", why);
+            return CommandResponse::Message("Could not load the leaderboard.".to_string());
+        }
+    };
+    if standings.is_empty() {
+        return CommandResponse::Message(
+            "Nobody's been tracked in this server in that time - start a session with /track!"
+                .to_string(),
+        );
+    }
+
+    let mut ranked: Vec<(f64, String, &Standing)> = standings
+        .iter()
+        .map(|standing| {
+            let (value, shown) = score(standing, stat);
+            (value, shown, standing)
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.2.name.cmp(&b.2.name)));
+
+    let lines: Vec<String> = ranked
+        .iter()
+        .take(LEADERBOARD_SIZE)
+        .enumerate()
+        .map(|(index, (_, shown, standing))| {
+            format!("**{}.** {} - {}", index + 1, standing.name, shown)
+        })
+        .collect();
+
+    let mut embed = CreateEmbed::default();
+    embed
+        .title(format!(
+            "{} leaderboard ({})",
+            stat_name(stat),
+            window_name(window)
+        ))
+        .description(lines.join("\n"));
+    CommandResponse::Embed(embed)
+}
+
+// The value a standing is ranked by for the stat, and how it's shown
+fn score(standing: &Standing, stat: &str) -> (f64, String) {
+    match stat {
+        "kdr" => {
+            let kdr = standing.kills as f64 / standing.deaths.max(1) as f64;
+            (kdr, format!("{:.2}", kdr))
+        }
+        "spree" => (standing.best_spree as f64, standing.best_spree.to_string()),
+        "pentas" => (standing.pentas as f64, standing.pentas.to_string()),
+        "revives" => (
+            standing.revives_given as f64,
+            standing.revives_given.to_string(),
+        ),
+        _ => (standing.kills as f64, standing.kills.to_string()),
+    }
+}
+
+fn stat_name(stat: &str) -> &'static str {
+    match stat {
+        "kdr" => "KDR",
+        "spree" => "Longest spree",
+        "pentas" => "Pentas",
+        "revives" => "Revives",
+        _ => "Kills",
+    }
+}
+
+fn window_name(window: &str) -> &'static str {
+    match window {
+        "daily" => "last 24 hours",
+        "weekly" => "last 7 days",
+        _ => "all time",
+    }
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("leaderboard")
+        .description("Show the top characters tracked in this server")
+        .create_option(|c| {
+            c.name("stat")
+                .description("What to rank characters by (kills if not given)")
+                .kind(CommandOptionType::String)
+                .add_string_choice("Kills", "kills")
+                .add_string_choice("KDR", "kdr")
+                .add_string_choice("Longest spree", "spree")
+                .add_string_choice("Pentas", "pentas")
+                .add_string_choice("Revives", "revives")
+                .required(false)
+        })
+        .create_option(|c| {
+            c.name("window")
+                .description("Which sessions to count (the last 7 days if not given)")
+                .kind(CommandOptionType::String)
+                .add_string_choice("Daily", "daily")
+                .add_string_choice("Weekly", "weekly")
+                .add_string_choice("All time", "all_time")
+                .required(false)
+        })
+}
@@ -0,0 +1,139 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::prelude::Context;
+
+use crate::census;
+use crate::repo::{LinkedCharacter, Repo};
+use crate::{CommandResponse, Database};
+
+// More than this and checking who's online takes more than one Census query
+const MAX_LINKED_CHARACTERS: usize = 10;
+
+pub async fn run(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+    options: &[CommandDataOption],
+) -> CommandResponse {
+    let option = |option_name: &str| {
+        options
+            .iter()
+            .find(|option| option.name == option_name)
+            .and_then(|option| option.value.as_ref())
+    };
+
+    let repo = ctx
+        .data
+        .read()
+        .await
+        .get::<Database>()
+        .cloned()
+        .expect("Unable to get the database in /link");
+    let user_id = interaction.user.id.0;
+
+    let character_name =
+        match option("character_name").and_then(|name| name.as_str()) {
+            Some(character_name) => character_name.trim(),
+            // Without a character, just list the linked ones
+            None => return CommandResponse::Message(match repo.linked_characters(user_id) {
+                Ok(linked) if linked.is_empty() => {
+                    "You haven't linked any characters yet - use /link with your character's name."
+                        .to_string()
+                }
+                Ok(linked) => format!(
+                    "Your linked characters: {}",
+                    linked
+                        .iter()
+                        .map(|character| character.name.clone())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                Err(why) => {
+                    println!("Could not get linked characters for /link: {}", why);
+                    "Could not get your linked characters.".to_string()
+                }
+            }),
+        };
+    let remove = option("remove")
+        .and_then(|remove| remove.as_bool())
+        .unwrap_or(false);
+
+    // Defer the interaction, since we have to wait on the Census
+    let _ = interaction.defer(&ctx.http).await;
+    CommandResponse::EditMessage(match do_run(&repo, user_id, character_name, remove).await {
+        Ok(message) => message,
+        Err(why) => why,
+    })
+}
+
+async fn do_run(
+    repo: &Repo,
+    user_id: u64,
+    character_name: &str,
+    remove: bool,
+) -> Result<String, String> {
+    let linked = repo.linked_characters(user_id)?;
+
+    if remove {
+        let character = linked
+            .iter()
+            .find(|character| character.name.to_lowercase() == character_name.to_lowercase());
+        return match character {
+            Some(character) => {
+                repo.unlink_character(user_id, character.character_id)?;
+                Ok(format!("Unlinked {}.", character.name))
+            }
+            None => Ok(format!("{} isn't linked to you.", character_name)),
+        };
+    }
+
+    // Make sure the character exists, and get their ID and properly capitalized name
+    let character = census::get_character(character_name).await?;
+    if linked
+        .iter()
+        .any(|linked| linked.character_id == character.character_id)
+    {
+        return Ok(format!("{} is already linked to you.", character.name));
+    }
+    if linked.len() >= MAX_LINKED_CHARACTERS {
+        return Ok(format!(
+            "You can link up to {} characters - unlink one first with `remove`.",
+            MAX_LINKED_CHARACTERS
+        ));
+    }
+
+    repo.link_character(
+        user_id,
+        &LinkedCharacter {
+            character_id: character.character_id,
+            name: character.name.clone(),
+        },
+    )?;
+    Ok(format!(
+        "Linked {} ({} on {}). Use /track without a character name to track whichever of your characters is online.",
+        character.name,
+        character.faction_name(),
+        character.world_name()
+    ))
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("link")
+        .description("Link your Planetside characters, so /track can find them without a name")
+        .create_option(|c| {
+            c.name("character_name")
+                .description("The character to link (leave empty to list your linked characters)")
+                .kind(CommandOptionType::String)
+                .min_length(3)
+                .required(false)
+        })
+        .create_option(|c| {
+            c.name("remove")
+                .description("Unlink the character instead")
+                .kind(CommandOptionType::Boolean)
+                .required(false)
+        })
+}
@@ -1,2 +1,7 @@
+pub mod config;
+pub mod leaderboard;
+pub mod link;
 pub mod ping;
+pub mod stats;
 pub mod track;
+pub mod volume;
@@ -0,0 +1,151 @@
+use serenity::builder::{CreateApplicationCommand, CreateEmbed};
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::prelude::Context;
+
+use crate::census;
+use crate::repo::CharacterTotals;
+use crate::{CommandResponse, Database};
+
+pub async fn run(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+    options: &[CommandDataOption],
+) -> CommandResponse {
+    match options.first() {
+        Some(&CommandDataOption {
+            ref name,
+            value: Some(ref value),
+            ..
+        }) if name == "character_name" => {
+            // For some reason, `value` has quotes surrounding it...
+            let value_string = value.to_string();
+            let character_name = value_string.trim_matches('"');
+
+            // Defer the interaction, since we have to wait on the Census
+            let _ = interaction.defer(&ctx.http).await;
+            match do_run(ctx, character_name).await {
+                Ok(embed) => CommandResponse::EditEmbed(embed),
+                Err(why) => CommandResponse::EditMessage(why),
+            }
+        }
+        _ => CommandResponse::Message("Please provide a character name".to_string()),
+    }
+}
+
+async fn do_run(ctx: &Context, character_name: &str) -> Result<CreateEmbed, String> {
+    let character = census::get_character(character_name).await?;
+    let repo = ctx
+        .data
+        .read()
+        .await
+        .get::<Database>()
+        .cloned()
+        .expect("Unable to get the database in /stats");
+    let totals = match repo.character_totals(character.character_id)? {
+        Some(totals) => totals,
+        None => {
+            return Err(format!(
+                "I haven't tracked {} yet - start a session with /track!",
+                character.name
+            ))
+        }
+    };
+    // The Census stats are just for comparison, so the rest of the stats are still worth showing without them
+    let lifetime = match census::get_lifetime_stats(character.character_id).await {
+        Ok(lifetime) => Some(lifetime),
+        Err(why) => {
+            println!("Could not get lifetime stats for /stats: {}", why);
+            None
+        }
+    };
+
+    let mut embed = CreateEmbed::default();
+    embed
+        .title(format!("{}'s blurber stats", character.name))
+        .color(character.faction_color())
+        .field("Sessions", totals.sessions, true)
+        .field("Time Tracked", format_hours(totals.tracked_secs), true)
+        .field("Kills", totals.kills, true)
+        .field("Deaths", totals.deaths, true)
+        .field(
+            "KDR",
+            format!("{:.2}", ratio(totals.kills, totals.deaths)),
+            true,
+        )
+        .field(
+            "Headshots",
+            format!("{:.0}%", ratio(totals.headshot_kills, totals.kills) * 100.0),
+            true,
+        )
+        .field("Longest Spree", totals.best_spree, true)
+        .field("Pentas", totals.pentas, true)
+        .field("Bastion Pulls", totals.bastion_pulls, true)
+        .field("Vehicles Destroyed", totals.vehicles_destroyed, true)
+        .field(
+            "Revives",
+            format!(
+                "{} given, {} received",
+                totals.revives_given, totals.revives_received
+            ),
+            true,
+        )
+        .field("Unlocks", totals.unlocks, true)
+        .field("Multikills", describe_multikills(&totals), false);
+
+    if let Some(lifetime) = lifetime {
+        let lifetime_kills = lifetime.get("kills").copied().unwrap_or_default();
+        let lifetime_deaths = lifetime.get("deaths").copied().unwrap_or_default();
+        embed.field(
+            "Lifetime (Census)",
+            format!(
+                "{} kills, {} deaths, {:.2} KDR over {}. The blurber has seen {:.1}% of their kills.",
+                lifetime_kills,
+                lifetime_deaths,
+                lifetime_kills as f64 / lifetime_deaths.max(1) as f64,
+                format_hours(lifetime.get("time").copied().unwrap_or_default()),
+                totals.kills as f64 / lifetime_kills.max(1) as f64 * 100.0
+            ),
+            false,
+        );
+    }
+
+    Ok(embed)
+}
+
+fn ratio(numerator: u32, denominator: u32) -> f64 {
+    numerator as f64 / denominator.max(1) as f64
+}
+
+fn format_hours(secs: u64) -> String {
+    format!("{}h {}m", secs / 3600, secs / 60 % 60)
+}
+
+fn describe_multikills(totals: &CharacterTotals) -> String {
+    if totals.multikills.is_empty() {
+        return "None yet".to_string();
+    }
+
+    let mut multikills: Vec<(&String, &u32)> = totals.multikills.iter().collect();
+    multikills.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    multikills
+        .iter()
+        .map(|(name, count)| format!("{} x{}", name, count))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("stats")
+        .description("Show what the bot has seen a character do across all of their sessions")
+        .create_option(|c| {
+            c.name("character_name")
+                .description("The character to show stats for")
+                .kind(CommandOptionType::String)
+                .min_length(3)
+                .required(true)
+        })
+}
@@ -1,73 +1,160 @@
+use std::collections::HashMap;
 use std::time::Duration;
 use std::{env, fs};
 
-use auraxis::api::client::{ApiClient, ApiClientConfig};
-use auraxis::api::{request::FilterType, CensusCollection};
-use auraxis::realtime::event::EventNames;
+use auraxis::realtime::event::{Event, EventNames};
 use auraxis::realtime::subscription::{
-    CharacterSubscription, EventSubscription, SubscriptionSettings,
+    CharacterSubscription, EventSubscription, SubscriptionSettings, WorldSubscription,
 };
 use auraxis::realtime::Service;
-use auraxis::CharacterID;
-use serenity::builder::CreateApplicationCommand;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
 use serenity::model::prelude::command::CommandOptionType;
 use serenity::model::prelude::interaction::application_command::{
     ApplicationCommandInteraction, CommandDataOption,
 };
+use serenity::model::prelude::{ChannelId, ChannelType};
 use serenity::prelude::Context;
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 
-use crate::events::{handle_event, OnLogout};
-use crate::{init_ess, CommandResponse, ESSClient, EventPatterns};
+use crate::census::{self, Character};
+use crate::events::{handle_event, handle_unlock, OnLogout, Session};
+use crate::feed;
+use crate::loudness;
+use crate::repo::Repo;
+use crate::rules::Manifest;
+use crate::stats;
+use crate::unlocks::Unlock;
+use crate::{
+    init_ess, CachedTracks, CommandResponse, Database, ESSClient, EventPatterns, SessionSender,
+    Volumes, Weapons,
+};
+
+// Used when the guild hasn't set its own timeout
+const DEFAULT_TIMEOUT_MINS: u32 = 5;
+
+// What a session's task gets woken up for
+enum Incoming {
+    Event(Event),
+    Unlock(Unlock),
+}
 
-const TIMEOUT_MINS: u8 = 5;
+// Whether /track asked for a kill feed, or left it up to the guild's settings
+enum FeedChoice {
+    On(ChannelId),
+    Off,
+    Default,
+}
 
 pub async fn run(
     interaction: &ApplicationCommandInteraction,
     ctx: &Context,
     options: &[CommandDataOption],
 ) -> CommandResponse {
-    let mut options = options.iter();
-    match (options.next(), options.next()) {
-        (
-            Some(&CommandDataOption {
-                ref name,
-                value: Some(ref value),
-                ..
-            }),
-            Some(&CommandDataOption {
-                name: ref name2,
-                value: Some(ref voicepack),
-                ..
-            }),
-        ) if name == "character_name" && name2 == "voicepack" => {
-            // For some reason, `value` has quotes surrounding it...
-            let value_string = value.to_string();
-            let character_name = value_string.trim_matches('"');
-
-            let value_string = voicepack.to_string();
-            let voicepack = value_string.trim_matches('"').to_string();
-
-            // Defer the interaction in case we take too long for a normal CHANNEL_MESSAGE_WITH_SOURCE
-            let _ = interaction.defer(&ctx.http).await;
-            CommandResponse::EditMessage(do_run(interaction, ctx, character_name, voicepack).await)
-        }
-        _ => CommandResponse::Message("Please provide a character name".to_string()),
-    }
+    let option = |option_name: &str| {
+        options
+            .iter()
+            .find(|option| option.name == option_name)
+            .and_then(|option| option.value.as_ref())
+    };
+
+    // Without a character name, one of the user's linked characters is tracked
+    let character_name = option("character_name")
+        .and_then(|name| name.as_str())
+        .map(|name| name.trim());
+
+    // Without one, the guild's default voicepack is used
+    let voicepack = option("voicepack")
+        .and_then(|voicepack| voicepack.as_str())
+        .map(|voicepack| voicepack.to_string());
+
+    // Choosing a channel for the kill feed turns it on, too
+    let feed_channel = option("feed_channel")
+        .and_then(|channel| channel.as_str())
+        .and_then(|channel| channel.parse().ok())
+        .map(ChannelId);
+    let feed_channel = match option("kill_feed").and_then(|feed| feed.as_bool()) {
+        Some(true) => FeedChoice::On(feed_channel.unwrap_or(interaction.channel_id)),
+        Some(false) => FeedChoice::Off,
+        None => feed_channel.map_or(FeedChoice::Default, FeedChoice::On),
+    };
+
+    // Defer the interaction in case we take too long for a normal CHANNEL_MESSAGE_WITH_SOURCE
+    let _ = interaction.defer(&ctx.http).await;
+    CommandResponse::EditMessage(
+        do_run(interaction, ctx, character_name, voicepack, feed_channel).await,
+    )
 }
 
 async fn do_run(
     interaction: &ApplicationCommandInteraction,
     ctx: &Context,
-    character_name: &str,
-    voicepack: String,
+    character_name: Option<&str>,
+    voicepack: Option<String>,
+    feed_channel: FeedChoice,
 ) -> String {
     let guild_id = match interaction.guild_id {
         Some(guild_id) => guild_id,
         None => return "Command only available in guilds.".to_string(),
     };
 
+    let repo = ctx
+        .data
+        .read()
+        .await
+        .get::<Database>()
+        .cloned()
+        .expect("Unable to get the database in /track");
+    let settings = match repo.guild_settings(guild_id.0) {
+        Ok(settings) => settings,
+        Err(why) => {
+            println!("Could not load guild settings in /track: {}", why);
+            return "Could not load this server's settings.".to_string();
+        }
+    };
+
+    // Some servers only let certain roles start sessions
+    if !settings.allowed_roles.is_empty() {
+        let allowed = interaction.member.as_ref().map_or(false, |member| {
+            member
+                .roles
+                .iter()
+                .any(|role| settings.allowed_roles.contains(&role.0))
+        });
+        if !allowed {
+            return "You don't have a role that's allowed to start tracking sessions in this server."
+                .to_string();
+        }
+    }
+
+    let character_name = match character_name {
+        Some(character_name) => match repo.alias(guild_id.0, character_name) {
+            Ok(Some(aliased)) => aliased,
+            Ok(None) => character_name.to_string(),
+            Err(why) => {
+                println!("Could not look up alias in /track: {}", why);
+                character_name.to_string()
+            }
+        },
+        None => match linked_character(&repo, interaction.user.id.0).await {
+            Ok(character_name) => character_name,
+            Err(why) => return why,
+        },
+    };
+    let timeout_mins = settings.timeout_mins.unwrap_or(DEFAULT_TIMEOUT_MINS);
+    let voicepack = match voicepack.or_else(|| settings.default_voicepack.clone()) {
+        Some(voicepack) => voicepack,
+        None => {
+            return "Please choose a voicepack (or have an admin set a default with /config voicepack)."
+                .to_string()
+        }
+    };
+    let feed_channel = match feed_channel {
+        FeedChoice::On(channel_id) => Some(channel_id),
+        FeedChoice::Off => None,
+        FeedChoice::Default => settings.feed_channel.map(ChannelId),
+    };
+
     let guild = if let Some(guild) = ctx.cache.guild(guild_id) {
         guild
     } else {
@@ -89,6 +176,12 @@ async fn do_run(
         .to_string();
     };
 
+    let mut manifest = match Manifest::load(&voicepack, guild_id.0) {
+        Ok(manifest) => manifest,
+        Err(why) => return format!("Could not load voicepack '{}': {}", voicepack, why),
+    };
+    manifest.apply_config(&settings.muted_categories, &settings.cooldowns);
+
     let manager = songbird::get(ctx)
         .await
         .expect("Songbird Voice client placed in at initialization.")
@@ -99,7 +192,7 @@ async fn do_run(
         if let Some(_) = call.lock().await.current_channel() {
             return format!("It looks like someone else in this server is currently tracking a character - you must wait for\
  them to logout or for their tracking session to expire ({} minutes of no events) before starting a new tracking\
- session in this server.", TIMEOUT_MINS).to_string();
+ session in this server.", timeout_mins).to_string();
         }
     }
     println!("about to join channel...takes a while now?");
@@ -107,36 +200,22 @@ async fn do_run(
     let _handler = manager.join(guild.id, connect_to).await;
 
     println!("joined channel...");
-    // Fetch character
-    let sid = env::var("SERVICE_ID").expect("Expected a service ID in the environment");
-    let mut client_config = ApiClientConfig::default();
-    client_config.service_id = Some(sid);
-
-    let client = ApiClient::new(client_config);
-
-    let query = client
-        .get(CensusCollection::Character)
-        .filter("name.first_lower", FilterType::EqualTo, character_name)
-        .limit(1)
-        .show("character_id")
-        .build();
-
-    let character_id = match query.await {
-        Ok(response) => {
-            let maybe_character_id = response
-                .items
-                .first()
-                .and_then(|v| v.get("character_id"))
-                .and_then(|v| v.as_str())
-                .and_then(|c| c.parse::<u64>().ok());
-
-            if let Some(character_id) = maybe_character_id {
-                character_id
-            } else {
-                return "Could not get character ID from Census response.".to_string();
-            }
-        }
-        Err(err) => return format!("Could not query the Census: {:?}", err).to_string(),
+    let character = match census::get_character(&character_name).await {
+        Ok(character) => character,
+        Err(why) => return why,
+    };
+    let character_id = character.character_id;
+    let world_id = character.world_id;
+
+    // Measuring runs ffmpeg over every new or changed track, so it's done in the background rather than holding up
+    // /track. Tracks that haven't been measured yet play at their own loudness until the next session.
+    let loudness = if manifest.playback.normalize_lufs.is_some() {
+        let measured = loudness::cached_measurements(&voicepack);
+        let voicepack = voicepack.clone();
+        tokio::task::spawn_blocking(move || loudness::measure_voicepack(&voicepack));
+        measured
+    } else {
+        HashMap::new()
     };
 
     let mut data = ctx.data.write().await;
@@ -144,11 +223,23 @@ async fn do_run(
         .get::<EventPatterns>()
         .cloned()
         .expect("Unable to get patterns in /track");
+    let volumes = data
+        .get::<Volumes>()
+        .cloned()
+        .expect("Unable to get volumes in /track");
+    // The guild's saved volume, unless /volume has changed it since the bot started
+    if let Some(volume) = settings.volume {
+        volumes.lock().await.entry(guild_id.0).or_insert(volume);
+    }
+    let track_cache = data
+        .get::<CachedTracks>()
+        .cloned()
+        .expect("Unable to get the track cache in /track");
 
     // If we get an error when trying to subscribe, our ESS socket has probably died, so try to reinitialize
     let ess_client = data.get_mut::<ESSClient>().unwrap();
     if let Err(_) = ess_client
-        .subscribe(character_subscription(character_id))
+        .subscribe(character_subscription(&character, &manifest))
         .await
     {
         println!("FAILED we're here now");
@@ -159,7 +250,7 @@ async fn do_run(
             .expect("Could not RE-initialize ESS client for /track");
 
         new_ess_client
-            .subscribe(character_subscription(character_id))
+            .subscribe(character_subscription(&character, &manifest))
             .await
             .expect("Could not resubscribe after RE-initialization of ESS client.");
 
@@ -178,15 +269,33 @@ async fn do_run(
     .to_string();
 
     let interaction_channel_id = interaction.channel_id.clone();
-    let char_name = character_name.to_string();
+    let char_name = character_name.clone();
     let data_clone = ctx.data.clone();
     let http = ctx.http.clone();
     tokio::task::spawn(async move {
-        let mut spree_count = 0;
-        let mut spree_timestamp = 0;
+        let mut session = Session::new(
+            character,
+            guild_id.0,
+            voicepack,
+            manifest,
+            volumes,
+            loudness,
+            track_cache,
+        );
+        session.feed =
+            feed_channel.map(|channel_id| feed::spawn(http.clone(), channel_id, char_name.clone()));
+        let (unlocks_tx, mut unlocks_rx) = mpsc::unbounded_channel();
+        session.unlocks = Some(unlocks_tx);
         let mut is_idle = false;
         while !is_idle {
-            let event = timeout(Duration::from_secs(60 * TIMEOUT_MINS as u64), rx.recv()).await;
+            // The session holds a sender for unlocks, so only the ESS channel closing ends the session
+            let incoming = async {
+                tokio::select! {
+                    event = rx.recv() => event.map(Incoming::Event),
+                    Some(unlock) = unlocks_rx.recv() => Some(Incoming::Unlock(unlock)),
+                }
+            };
+            let event = timeout(Duration::from_secs(60 * timeout_mins as u64), incoming).await;
             if let Err(_) = event {
                 is_idle = true;
 
@@ -194,7 +303,7 @@ async fn do_run(
                     .send_message(&http, |m| {
                         m.content(format!(
                             "No events detected for {} after {} minutes, disconnecting now.",
-                            char_name, TIMEOUT_MINS
+                            char_name, timeout_mins
                         ))
                     })
                     .await;
@@ -207,7 +316,7 @@ async fn do_run(
                     .expect("Unable to get patterns in /track");
                 let mut patterns = patterns.lock().await;
                 patterns.remove(&character_id);
-            } else if let Ok(Some(event)) = event {
+            } else if let Ok(Some(incoming)) = event {
                 let logout_handler = OnLogout {
                     character_id: character_id.clone(),
                     channel_id: interaction_channel_id.clone(),
@@ -217,27 +326,35 @@ async fn do_run(
                     manager: manager.clone(),
                     data_clone: data_clone.clone(),
                 };
-                handle_event(
-                    &event,
-                    &character_id,
-                    &guild_id.0,
-                    &mut spree_count,
-                    &mut spree_timestamp,
-                    &voicepack,
-                    &manager,
-                    logout_handler,
-                )
-                .await;
+                let event = match incoming {
+                    Incoming::Event(event) => event,
+                    Incoming::Unlock(unlock) => {
+                        handle_unlock(unlock, &mut session, &manager, logout_handler).await;
+                        continue;
+                    }
+                };
+                handle_event(&event, &mut session, &manager, logout_handler).await;
+
+                let data = data_clone.read().await;
+                let weapons = data.get::<Weapons>().unwrap();
+                session
+                    .stats
+                    .record(&event, character_id, session.spree.count, weapons);
             } else {
                 // We got Ok(None), which most likely means the player logged out and the tx was closed.
                 // So, we should set is_idle = true to end the loop and thus the thread.
                 is_idle = true;
             }
         }
+
+        stats::send_summary(&session, interaction_channel_id, &http).await;
+        if let Err(why) = repo.record_session(&session) {
+            println!("Could not record session in the database: {}", why);
+        }
     });
 
     // Add entry to cached patterns
-    patterns.insert(character_id, tx);
+    patterns.insert(character_id, SessionSender { world_id, tx });
 
     return success_message;
 }
@@ -248,62 +365,132 @@ pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicatio
         .description("Track a character")
         .create_option(|c| {
             c.name("character_name")
-                .description("Specify the character name you would like to track")
+                .description("The character name or alias to track (your online linked character if not given)")
                 .kind(CommandOptionType::String)
                 .min_length(3)
-                .required(true)
+                .required(false)
         })
         .create_option(|c| {
             c.name("voicepack")
-                .description("Specify the voicepack you would like to use")
+                .description("Specify the voicepack you would like to use (this server's default if not given)")
                 .kind(CommandOptionType::String)
                 .min_length(1)
-                .required(true);
-
-            // Read the /voicepacks dir and dynamically create options
-            // this is a monstrosity, I know. If only Rust had with statements...
-            let pwd = env::current_dir().expect("Could not get pwd.");
-            let pwd = pwd.display();
-
-            match fs::read_dir(format!("{}/voicepacks", pwd)) {
-                Ok(ls) => {
-                    for file in ls {
-                        if let Ok(file) = file {
-                            let filename = file.file_name();
-                            let filename = filename
-                                .to_str()
-                                .expect("Could not convert filename to str");
-
-                            if let Ok(file_type) = file.file_type() {
-                                if file_type.is_dir() && filename != "TEMPLATE" {
-                                    c.add_string_choice(filename, filename);
-                                }
-                            }
+                .required(false);
+            add_voicepack_choices(c)
+        })
+        .create_option(|c| {
+            c.name("kill_feed")
+                .description("Post your kills, deaths and unlocks in this channel")
+                .kind(CommandOptionType::Boolean)
+                .required(false)
+        })
+        .create_option(|c| {
+            c.name("feed_channel")
+                .description("Post the kill feed in this channel instead")
+                .kind(CommandOptionType::Channel)
+                .channel_types(&[ChannelType::Text])
+                .required(false)
+        })
+}
+
+// The name of the user's linked character that's online, or of their only one
+async fn linked_character(repo: &Repo, user_id: u64) -> Result<String, String> {
+    let linked = repo.linked_characters(user_id).map_err(|why| {
+        println!("Could not get linked characters in /track: {}", why);
+        "Could not get your linked characters.".to_string()
+    })?;
+
+    match linked.as_slice() {
+        [] => Err(
+            "Please provide a character name (or link your characters with /link to leave it out)."
+                .to_string(),
+        ),
+        [character] => Ok(character.name.clone()),
+        _ => {
+            let ids: Vec<u64> = linked
+                .iter()
+                .map(|character| character.character_id)
+                .collect();
+            let online = census::get_online_characters(&ids).await?;
+            linked
+                .iter()
+                .find(|character| online.contains(&character.character_id))
+                .map(|character| character.name.clone())
+                .ok_or_else(|| {
+                    format!(
+                        "None of your linked characters ({}) are online - log in first, or provide a character name.",
+                        linked
+                            .iter()
+                            .map(|character| character.name.clone())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                })
+        }
+    }
+}
+
+// Read the /voicepacks dir and dynamically create options
+pub fn add_voicepack_choices(
+    c: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    let pwd = env::current_dir().expect("Could not get pwd.");
+    let pwd = pwd.display();
+
+    match fs::read_dir(format!("{}/voicepacks", pwd)) {
+        Ok(ls) => {
+            for file in ls {
+                if let Ok(file) = file {
+                    let filename = file.file_name();
+                    let filename = filename
+                        .to_str()
+                        .expect("Could not convert filename to str");
+
+                    if let Ok(file_type) = file.file_type() {
+                        if file_type.is_dir() && filename != "TEMPLATE" {
+                            c.add_string_choice(filename, filename);
                         }
                     }
                 }
-                Err(why) => {
-                    panic!("I could not read the voicepacks dir: {:?}", why);
-                }
             }
-            c
-        })
+        }
+        Err(why) => {
+            panic!("I could not read the voicepacks dir: {:?}", why);
+        }
+    }
+    c
 }
 
-fn character_subscription(character_id: CharacterID) -> SubscriptionSettings {
+// Subscribes to the character's events, and to world events (alerts, continent locks) on the character's world
+fn character_subscription(character: &Character, manifest: &Manifest) -> SubscriptionSettings {
+    let mut event_names = vec![
+        EventNames::PlayerLogin,
+        EventNames::PlayerLogout,
+        EventNames::Death,
+        EventNames::VehicleDestroy,
+        EventNames::ItemAdded,
+        EventNames::PlayerFacilityCapture,
+        EventNames::PlayerFacilityDefend,
+        EventNames::MetagameEvent,
+        EventNames::ContinentLock,
+        EventNames::ContinentUnlock,
+        EventNames::BattleRankUp,
+        EventNames::AchievementEarned,
+        EventNames::SkillAdded,
+    ];
+    event_names.extend(
+        manifest
+            .experience_ids()
+            .into_iter()
+            .map(EventNames::GainExperienceId),
+    );
+
     SubscriptionSettings {
-        event_names: Some(EventSubscription::Ids(vec![
-            EventNames::PlayerLogin,
-            EventNames::PlayerLogout,
-            EventNames::Death,
-            EventNames::VehicleDestroy,
-            EventNames::ItemAdded,
-            EventNames::GainExperienceId(7),  // Revive
-            EventNames::GainExperienceId(53), // Squad Revive
-        ])),
-        characters: Some(CharacterSubscription::Ids(vec![character_id])),
-        worlds: None,
-        logical_and_characters_with_worlds: None,
+        event_names: Some(EventSubscription::Ids(event_names)),
+        characters: Some(CharacterSubscription::Ids(vec![character.character_id])),
+        worlds: Some(WorldSubscription::Ids(vec![character.world_id])),
+        // Otherwise we'd get character events (e.g. every death) for everyone on the world
+        logical_and_characters_with_worlds: Some(true),
         service: Service::Event,
     }
 }
@@ -0,0 +1,121 @@
+use serenity::builder::CreateApplicationCommand;
+use serenity::model::prelude::command::CommandOptionType;
+use serenity::model::prelude::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::model::prelude::GuildId;
+use serenity::prelude::Context;
+
+use crate::playback::TrackGain;
+use crate::{CommandResponse, Database, Volumes};
+
+pub const MAX_PERCENT: i64 = 200;
+
+pub async fn run(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+    options: &[CommandDataOption],
+) -> CommandResponse {
+    let guild_id = match interaction.guild_id {
+        Some(guild_id) => guild_id,
+        None => return CommandResponse::Message("Command only available in guilds.".to_string()),
+    };
+
+    let (volumes, repo) = {
+        let data = ctx.data.read().await;
+        let volumes = data
+            .get::<Volumes>()
+            .cloned()
+            .expect("Unable to get volumes in /volume");
+        let repo = data
+            .get::<Database>()
+            .cloned()
+            .expect("Unable to get the database in /volume");
+        (volumes, repo)
+    };
+    let mut settings = match repo.guild_settings(guild_id.0) {
+        Ok(settings) => settings,
+        Err(why) => {
+            println!("Could not load guild settings in /volume: {}", why);
+            return CommandResponse::Message("Could not load this server's settings.".to_string());
+        }
+    };
+
+    let percent = match options.first() {
+        Some(&CommandDataOption {
+            ref name,
+            value: Some(ref value),
+            ..
+        }) if name == "percent" => value.as_i64(),
+        _ => None,
+    };
+
+    // Without a percent, just report the current volume
+    let volume = match percent {
+        Some(percent) => percent.clamp(0, MAX_PERCENT) as f32 / 100.0,
+        None => {
+            let volume = volumes
+                .lock()
+                .await
+                .get(&guild_id.0)
+                .copied()
+                .or(settings.volume)
+                .unwrap_or(1.0);
+            return CommandResponse::Message(format!(
+                "The volume is at {}%.",
+                (volume * 100.0).round()
+            ));
+        }
+    };
+    settings.volume = Some(volume);
+    if let Err(why) = repo.save_guild_settings(&settings) {
+        println!("Could not save the volume: {}", why);
+    }
+    apply(ctx, guild_id, volume).await;
+
+    CommandResponse::Message(format!("Set the volume to {}%.", (volume * 100.0).round()))
+}
+
+// Sets the guild's volume for new lines, and for the lines that are already playing or waiting
+pub async fn apply(ctx: &Context, guild_id: GuildId, volume: f32) {
+    let volumes = ctx
+        .data
+        .read()
+        .await
+        .get::<Volumes>()
+        .cloned()
+        .expect("Unable to get volumes in /volume");
+    volumes.lock().await.insert(guild_id.0, volume);
+
+    let manager = songbird::get(ctx)
+        .await
+        .expect("Songbird Voice client placed in at initialization.")
+        .clone();
+    if let Some(call) = manager.get(guild_id) {
+        let handles = call.lock().await.queue().current_queue();
+        for handle in handles {
+            let gain = handle
+                .typemap()
+                .read()
+                .await
+                .get::<TrackGain>()
+                .copied()
+                .unwrap_or(1.0);
+            let _ = handle.set_volume(volume * gain);
+        }
+    }
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("volume")
+        .description("Set how loud the bot plays in this server")
+        .create_option(|c| {
+            c.name("percent")
+                .description("The volume, from 0 to 200% (leave empty to see the current volume)")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(0)
+                .max_int_value(MAX_PERCENT)
+                .required(false)
+        })
+}
@@ -1,12 +1,5 @@
-use auraxis::realtime::event::Death;
-use auraxis::realtime::event::GainExperience;
-
 use auraxis::realtime::event::Event;
-use auraxis::realtime::event::ItemAdded;
-use auraxis::realtime::event::VehicleDestroy;
-use rand::rngs::StdRng;
-use rand::seq::IteratorRandom;
-use rand::SeedableRng;
+use chrono::{DateTime, Utc};
 use serenity::async_trait;
 use serenity::http::Http;
 use serenity::model::prelude::*;
@@ -16,185 +9,806 @@ use songbird::EventContext;
 use songbird::EventHandler as VoiceEventHandler;
 use songbird::Songbird;
 use songbird::TrackEvent;
-use std::env;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::census::{self, Character};
+use crate::feed::{Feed, FeedEntry, FeedKind};
+use crate::loudness;
+use crate::playback::{self, Line, TrackPicker};
+use crate::rules::{CalloutMode, Fields, Manifest, MultikillTier};
+use crate::stats::SessionStats;
+use crate::track_cache::SharedTrackCache;
+use crate::tts;
+use crate::unlocks::{self, Unlock, Unlocks};
 use crate::EventPatterns;
-use crate::WeaponIds;
+use crate::FacilityTypes;
+use crate::Vehicles;
+use crate::Weapons;
 
-// a killing spree ends after this amount of seconds of no kills
-const KILLING_SPREE_INTERVAL: i64 = 12;
+// A login after this many days away counts as "returning"
+const RETURNING_AFTER_DAYS: i64 = 7;
 
-async fn handle_revive(ge: &GainExperience, char_id: &u64) -> Option<String> {
-    if ge.character_id == *char_id {
-        Some("revive_teammate".to_string())
-    } else if ge.other_id == *char_id {
-        Some("get_revived".to_string())
-    } else {
-        None
+// The state of a single tracking session, owned by the session's task in /track
+pub struct Session {
+    pub character: Character,
+    pub guild_id: u64,
+    pub voicepack: String,
+    pub manifest: Manifest,
+    pub spree: Spree,
+    // multikill tiers (by kill count) already announced in the text channel this session
+    pub announced_tiers: HashSet<u16>,
+    // what's been played recently, for cooldowns and the throttle
+    pub history: PlayHistory,
+    // lines enqueued in the call that may still be waiting or playing
+    pub lines: Vec<Line>,
+    // how the next track is picked in each category
+    pub pickers: HashMap<String, TrackPicker>,
+    // every guild's volume, set with /volume
+    pub volumes: Arc<Mutex<HashMap<u64, f32>>>,
+    // the measured loudness of each of the voicepack's tracks, for normalization
+    pub loudness: HashMap<String, f64>,
+    // decoded tracks, shared with every other session
+    pub track_cache: SharedTrackCache,
+    // the text channel kill feed, if it's turned on for this session
+    pub feed: Option<Feed>,
+    // where unlocks found in the Census are sent back to the session
+    pub unlocks: Option<Unlocks>,
+    // when the latest achievement and skill we've played a line for were unlocked
+    pub last_achievement: DateTime<Utc>,
+    pub last_skill: DateTime<Utc>,
+    pub stats: SessionStats,
+}
+
+impl Session {
+    pub fn new(
+        character: Character,
+        guild_id: u64,
+        voicepack: String,
+        manifest: Manifest,
+        volumes: Arc<Mutex<HashMap<u64, f32>>>,
+        loudness: HashMap<String, f64>,
+        track_cache: SharedTrackCache,
+    ) -> Session {
+        Session {
+            character,
+            guild_id,
+            voicepack,
+            manifest,
+            spree: Spree::default(),
+            announced_tiers: HashSet::new(),
+            history: PlayHistory::default(),
+            lines: Vec::new(),
+            pickers: HashMap::new(),
+            volumes,
+            loudness,
+            track_cache,
+            feed: None,
+            unlocks: None,
+            last_achievement: Utc::now(),
+            last_skill: Utc::now(),
+            stats: SessionStats::new(),
+        }
+    }
+
+    pub async fn volume(&self) -> f32 {
+        let volumes = self.volumes.lock().await;
+        volumes.get(&self.guild_id).copied().unwrap_or(1.0)
+    }
+
+    // The volume to play a track at relative to the session's volume, from loudness normalization and the track's gain
+    pub fn track_gain(&self, track_name: &str) -> f32 {
+        let mut gain_db = self.manifest.track_gain_db(track_name);
+        if let (Some(target_lufs), Some(lufs)) = (
+            self.manifest.playback.normalize_lufs,
+            self.loudness.get(track_name),
+        ) {
+            gain_db += loudness::normalization_gain(*lufs, target_lufs);
+        }
+        loudness::db_to_linear(gain_db)
     }
 }
 
-async fn handle_death(
-    death: &Death,
-    char_id: &u64,
-    spree_count: &mut u16,
-    spree_timestamp: &mut u32,
-) -> Option<String> {
-    if &death.character_id == char_id {
-        if death.character_id == death.attacker_character_id {
-            Some("suicide".to_string())
-        } else {
-            Some("death".to_string())
-        }
-    } else if &death.attacker_character_id == char_id {
-        let kill_category =
-            if *spree_timestamp > (death.timestamp.timestamp() - KILLING_SPREE_INTERVAL) as u32 {
-                *spree_count += 1;
-                match (*spree_count + 1, death.is_headshot) {
-                    (1, true) => "kill_headshot",
-                    (1, _) => "kill",
-                    (2, _) => "kill_double",
-                    (3, _) => "kill_triple",
-                    (4, _) => "kill_quad",
-                    _ => "kill_penta",
+// When a session played its lines, for cooldowns and the throttle
+#[derive(Default)]
+pub struct PlayHistory {
+    // when each category was last played
+    last_played: HashMap<String, Instant>,
+    // when each line in the last minute was played
+    recent_plays: VecDeque<Instant>,
+}
+
+impl PlayHistory {
+    // Whether playing `category` at `now` would break its cooldown or the session's throttle. Lines that jump the
+    // queue (see [playback] in `voicepacks/defaults.toml`) aren't throttled, so a penta isn't lost to a busy minute,
+    // though they still have cooldowns.
+    fn is_throttled(&mut self, category: &str, manifest: &Manifest, now: Instant) -> bool {
+        let minute = Duration::from_secs(60);
+        while let Some(played_at) = self.recent_plays.front() {
+            if now.duration_since(*played_at) < minute {
+                break;
+            }
+            self.recent_plays.pop_front();
+        }
+
+        let policy = &manifest.playback;
+        if policy.priority(category) < policy.jump_priority() {
+            let throttle = &manifest.throttle;
+            if let (Some(min_gap), Some(played_at)) =
+                (throttle.min_gap_secs, self.recent_plays.back())
+            {
+                if now.duration_since(*played_at) < Duration::from_secs(min_gap) {
+                    return true;
                 }
-            } else {
-                *spree_count = 1;
-                if death.is_headshot {
-                    "kill_headshot"
-                } else {
-                    "kill"
+            }
+            if let Some(max_per_minute) = throttle.max_per_minute {
+                if self.recent_plays.len() >= max_per_minute {
+                    return true;
                 }
-            };
+            }
+        }
 
-        *spree_timestamp = death.timestamp.timestamp() as u32;
-        Some(kill_category.to_string())
-    } else {
-        None
+        match (
+            manifest.cooldowns.get(category),
+            self.last_played.get(category),
+        ) {
+            (Some(cooldown), Some(played_at)) => {
+                now.duration_since(*played_at) < Duration::from_secs(*cooldown)
+            }
+            _ => false,
+        }
     }
-}
 
-async fn handle_vehicle_destroy(vd: &VehicleDestroy, char_id: &u64) -> Option<String> {
-    if &vd.character_id == char_id && vd.character_id == vd.attacker_character_id {
-        Some("destroy_own_vehicle".to_string())
-    } else if &vd.attacker_character_id == char_id {
-        Some("destroy_vehicle".to_string())
-    } else {
-        None
+    fn record(&mut self, category: String, now: Instant) {
+        self.last_played.insert(category, now);
+        self.recent_plays.push_back(now);
     }
 }
 
-async fn handle_item_added(
-    ia: &ItemAdded,
-    char_id: &u64,
-    logout_handler: OnLogout,
-) -> Option<String> {
-    if &ia.character_id == char_id {
+// Flattens an event into the fields rules can match on. See `voicepacks/defaults.toml` for what's available.
+fn event_fields(
+    event: &Event,
+    session: &Session,
+    data: &TypeMap,
+) -> Option<(&'static str, Fields)> {
+    let char_id = &session.character.character_id;
+    let mut fields = Fields::new();
+    let event_name = match event {
+        Event::Death(death) => {
+            let weapons = data.get::<Weapons>().unwrap();
+            let vehicles = data.get::<Vehicles>().unwrap();
+
+            fields.insert("self_character", (&death.character_id == char_id).into());
+            fields.insert(
+                "self_attacker",
+                (&death.attacker_character_id == char_id).into(),
+            );
+            fields.insert(
+                "same_character",
+                (death.character_id == death.attacker_character_id).into(),
+            );
+            fields.insert("is_headshot", death.is_headshot.into());
+            fields.insert("attacker_weapon_id", death.attacker_weapon_id.into());
+            fields.insert("attacker_vehicle_id", death.attacker_vehicle_id.into());
+            fields.insert("attacker_fire_mode_id", death.attacker_fire_mode_id.into());
+            fields.insert("attacker_loadout_id", death.attacker_loadout_id.into());
+            if let Some(vehicle) = vehicles.get(&death.attacker_vehicle_id) {
+                fields.insert("attacker_vehicle_type", vehicle.kind.as_str().into());
+            }
+            if let Some(weapon) = weapons.get(&death.attacker_weapon_id) {
+                fields.insert("weapon_category", weapon.category.as_str().into());
+            }
+            "Death"
+        }
+        Event::VehicleDestroy(vd) => {
+            let vehicles = data.get::<Vehicles>().unwrap();
+            let vehicle_type = vehicles
+                .get(&vd.vehicle_id)
+                .map_or("other", |vehicle| vehicle.kind.as_str());
+
+            fields.insert("self_character", (&vd.character_id == char_id).into());
+            fields.insert(
+                "self_attacker",
+                (&vd.attacker_character_id == char_id).into(),
+            );
+            fields.insert(
+                "same_character",
+                (vd.character_id == vd.attacker_character_id).into(),
+            );
+            fields.insert("vehicle_id", vd.vehicle_id.into());
+            fields.insert("vehicle_type", vehicle_type.into());
+            fields.insert("attacker_weapon_id", vd.attacker_weapon_id.into());
+            fields.insert("attacker_vehicle_id", vd.attacker_vehicle_id.into());
+            "VehicleDestroy"
+        }
+        Event::GainExperience(ge) => {
+            fields.insert("self_character", (&ge.character_id == char_id).into());
+            fields.insert("self_other", (&ge.other_id == char_id).into());
+            fields.insert("experience_id", ge.experience_id.into());
+            "GainExperience"
+        }
+        Event::ItemAdded(ia) => {
+            let weapons = data.get::<Weapons>().unwrap();
+
+            fields.insert("self_character", (&ia.character_id == char_id).into());
+            fields.insert("item_id", ia.item_id.into());
+            fields.insert("context", ia.context.as_str().into());
+            fields.insert("is_weapon", weapons.contains_key(&ia.item_id).into());
+            "ItemAdded"
+        }
+        Event::PlayerFacilityCapture(pfc) => {
+            let facility_types = data.get::<FacilityTypes>().unwrap();
+
+            fields.insert("self_character", (&pfc.character_id == char_id).into());
+            fields.insert("facility_id", pfc.facility_id.into());
+            if let Some(facility_type) = facility_types.get(&pfc.facility_id) {
+                fields.insert("facility_type", facility_type.as_str().into());
+            }
+            "PlayerFacilityCapture"
+        }
+        Event::PlayerFacilityDefend(pfd) => {
+            let facility_types = data.get::<FacilityTypes>().unwrap();
+
+            fields.insert("self_character", (&pfd.character_id == char_id).into());
+            fields.insert("facility_id", pfd.facility_id.into());
+            if let Some(facility_type) = facility_types.get(&pfd.facility_id) {
+                fields.insert("facility_type", facility_type.as_str().into());
+            }
+            "PlayerFacilityDefend"
+        }
+        Event::PlayerLogin(login) => {
+            // `last_login` is from when the session started, so it's still the login before this one
+            let last_login = session.character.last_login;
+            let first_login_today = last_login.map_or(true, |last_login| {
+                last_login.naive_utc().date() < login.timestamp.naive_utc().date()
+            });
+            let returning = last_login.map_or(false, |last_login| {
+                login.timestamp - last_login > chrono::Duration::days(RETURNING_AFTER_DAYS)
+            });
+
+            fields.insert("self_character", (&login.character_id == char_id).into());
+            fields.insert("first_login_today", first_login_today.into());
+            fields.insert("returning", returning.into());
+            "PlayerLogin"
+        }
+        Event::PlayerLogout(logout) => {
+            fields.insert("self_character", (&logout.character_id == char_id).into());
+            "PlayerLogout"
+        }
+        // World events are only sent to sessions on their world, but check anyway
+        Event::MetagameEvent(me) if me.world_id == session.character.world_id => {
+            let own_share = match session.character.faction_id {
+                1 => Some(me.faction_vs),
+                2 => Some(me.faction_nc),
+                3 => Some(me.faction_tr),
+                _ => None,
+            };
+            let winning_share = me.faction_vs.max(me.faction_nc).max(me.faction_tr);
+
+            fields.insert("metagame_event_id", me.metagame_event_id.into());
+            fields.insert(
+                "metagame_event_state_name",
+                me.metagame_event_state_name.as_str().into(),
+            );
+            fields.insert("zone_id", me.zone_id.into());
+            // NSO fight for whichever empire they're lent to, and the alert doesn't say which, so they neither win nor
+            // lose it
+            if let Some(own_share) = own_share {
+                fields.insert("own_faction_won", (own_share == winning_share).into());
+            }
+            "MetagameEvent"
+        }
+        Event::ContinentLock(lock) if lock.world_id == session.character.world_id => {
+            fields.insert("zone_id", lock.zone_id.into());
+            fields.insert(
+                "own_faction",
+                (lock.triggering_faction == session.character.faction_id).into(),
+            );
+            "ContinentLock"
+        }
+        Event::ContinentUnlock(unlock) if unlock.world_id == session.character.world_id => {
+            fields.insert("zone_id", unlock.zone_id.into());
+            "ContinentUnlock"
+        }
+        _ => return None,
+    };
+
+    Some((event_name, fields))
+}
+
+// Values for the placeholders in a TTS template (see `voicepacks/defaults.toml`). Names of other characters cost a
+// Census request each, so they're only looked up if the template uses them.
+async fn template_values(
+    event: Option<&Event>,
+    template: &str,
+    tier_name: Option<&str>,
+    session: &Session,
+    logout_handler: &OnLogout,
+) -> HashMap<&'static str, String> {
+    let mut values = HashMap::new();
+    values.insert("character", session.character.name.clone());
+    if let Some(tier_name) = tier_name {
+        values.insert("tier", tier_name.to_string());
+    }
+
+    let (victim_id, attacker_id, weapon_id, vehicle_id) = match event {
+        Some(Event::Death(death)) => (
+            death.character_id,
+            death.attacker_character_id,
+            death.attacker_weapon_id,
+            death.attacker_vehicle_id,
+        ),
+        Some(Event::VehicleDestroy(vd)) => (
+            vd.character_id,
+            vd.attacker_character_id,
+            vd.attacker_weapon_id,
+            vd.vehicle_id,
+        ),
+        _ => return values,
+    };
+
+    {
         let data = logout_handler.data_clone.read().await;
-        let weapon_ids = data.get::<WeaponIds>().unwrap();
+        let weapons = data.get::<Weapons>().unwrap();
+        let vehicles = data.get::<Vehicles>().unwrap();
+        if let Some(weapon) = weapons.get(&weapon_id) {
+            values.insert("weapon", weapon.name.clone());
+        }
+        if let Some(vehicle) = vehicles.get(&vehicle_id) {
+            values.insert("vehicle", vehicle.name.clone());
+        }
+    }
 
-        if ia.context == "CaptureTheFlag.TakeFlag" {
-            Some("ctf_flag_take".to_string())
-        } else if ia.context == "GuildBankWithdrawal" && ia.item_id == 6008913 {
-            Some("bastion_pull".to_string())
-        } else if weapon_ids.contains(&ia.item_id) {
-            Some("unlock_weapon".to_string())
-        } else {
-            Some("unlock_any".to_string())
+    for (placeholder, character_id) in [("victim", victim_id), ("attacker", attacker_id)] {
+        if !template.contains(&format!("{{{}}}", placeholder)) {
+            continue;
         }
-    } else {
-        None
+        if character_id == session.character.character_id {
+            values.insert(placeholder, session.character.name.clone());
+        } else if character_id != 0 {
+            match census::get_character_by_id(character_id).await {
+                Ok(character) => {
+                    values.insert(placeholder, character.name);
+                }
+                Err(why) => println!("Could not look up {} for TTS: {}", placeholder, why),
+            }
+        }
+    }
+
+    values
+}
+
+// What the kill feed shows for an event, if anything
+fn feed_entry(event: &Event, session: &Session, data: &TypeMap) -> Option<FeedEntry> {
+    let char_id = session.character.character_id;
+    let weapons = data.get::<Weapons>().unwrap();
+    let vehicles = data.get::<Vehicles>().unwrap();
+    let weapon_name = |weapon_id| weapons.get(&weapon_id).map(|weapon| weapon.name.clone());
+    let vehicle_name = |vehicle_id| {
+        vehicles
+            .get(&vehicle_id)
+            .map(|vehicle| vehicle.name.clone())
+    };
+
+    let entry = match event {
+        Event::Death(death) if death.attacker_character_id == char_id => FeedEntry {
+            kind: if death.character_id == char_id {
+                FeedKind::Death
+            } else {
+                FeedKind::Kill
+            },
+            other_id: Some(death.character_id).filter(|id| *id != char_id),
+            weapon: weapon_name(death.attacker_weapon_id),
+            vehicle: vehicle_name(death.attacker_vehicle_id),
+            is_headshot: death.is_headshot,
+        },
+        Event::Death(death) if death.character_id == char_id => FeedEntry {
+            kind: FeedKind::Death,
+            other_id: Some(death.attacker_character_id).filter(|id| *id != 0),
+            weapon: weapon_name(death.attacker_weapon_id),
+            vehicle: vehicle_name(death.attacker_vehicle_id),
+            is_headshot: death.is_headshot,
+        },
+        Event::VehicleDestroy(vd)
+            if vd.attacker_character_id == char_id && vd.character_id != char_id =>
+        {
+            FeedEntry {
+                kind: FeedKind::VehicleKill,
+                other_id: Some(vd.character_id).filter(|id| *id != 0),
+                weapon: weapon_name(vd.attacker_weapon_id),
+                vehicle: vehicle_name(vd.vehicle_id),
+                is_headshot: false,
+            }
+        }
+        Event::VehicleDestroy(vd)
+            if vd.character_id == char_id && vd.attacker_character_id != char_id =>
+        {
+            FeedEntry {
+                kind: FeedKind::VehicleLost,
+                other_id: Some(vd.attacker_character_id).filter(|id| *id != 0),
+                weapon: weapon_name(vd.attacker_weapon_id),
+                vehicle: vehicle_name(vd.vehicle_id),
+                is_headshot: false,
+            }
+        }
+        // Lots of things are "items", so only weapon unlocks make it to the feed
+        Event::ItemAdded(ia) if ia.character_id == char_id => FeedEntry {
+            kind: FeedKind::Unlock,
+            other_id: None,
+            weapon: Some(weapon_name(ia.item_id)?),
+            vehicle: None,
+            is_headshot: false,
+        },
+        _ => return None,
+    };
+
+    Some(entry)
+}
+
+#[derive(Default)]
+pub struct Spree {
+    pub count: u16,
+    pub last_kill: Option<DateTime<Utc>>,
+}
+
+impl Spree {
+    // Records a kill at `timestamp`, returning how many kills in a row the spree is now at
+    pub fn record_kill(&mut self, timestamp: DateTime<Utc>, window: chrono::Duration) -> u16 {
+        match self.last_kill {
+            Some(last_kill) if timestamp - last_kill <= window => self.count += 1,
+            _ => self.count = 1,
+        }
+        self.last_kill = Some(timestamp);
+        self.count
+    }
+
+    // Records a kill within the manifest's multikill window, returning the tiers the spree has now reached, highest
+    // first
+    pub fn climb<'a>(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        manifest: &'a Manifest,
+    ) -> Vec<&'a MultikillTier> {
+        let window =
+            chrono::Duration::seconds(manifest.multikill.window_secs.unwrap_or_default() as i64);
+        let kills = self.record_kill(timestamp, window);
+        manifest.multikill_tiers(kills)
     }
 }
 
 pub async fn handle_event(
     event: &Event,
-    char_id: &u64,
-    guild_id: &u64,
-    spree_count: &mut u16,
-    spree_timestamp: &mut u32,
-    voicepack: &String,
+    session: &mut Session,
     manager: &Arc<Songbird>,
     logout_handler: OnLogout,
 ) {
-    let maybe_category = match &event {
-        // Revive GEs
-        Event::GainExperience(ge) => {
-            if ge.experience_id == 7 || ge.experience_id == 53 {
-                handle_revive(ge, char_id).await
-            } else {
-                None
-            }
+    if let Event::BattleRankUp | Event::AchievementEarned | Event::SkillAdded = event {
+        // These don't say whose they are, so the Census is checked in the background and anything found comes back
+        // through `handle_unlock`
+        if let Some(unlocks) = &session.unlocks {
+            let since = match event {
+                Event::SkillAdded => session.last_skill,
+                _ => session.last_achievement,
+            };
+            tokio::spawn(unlocks::check(
+                event.clone(),
+                session.character.clone(),
+                since,
+                unlocks.clone(),
+            ));
         }
-        Event::Death(death) => handle_death(death, char_id, spree_count, spree_timestamp).await,
-        Event::VehicleDestroy(vd) => handle_vehicle_destroy(vd, char_id).await,
-        Event::PlayerLogin(login) if &login.character_id == char_id => Some("login".to_string()),
-        Event::PlayerLogout(logout) => {
-            if &logout.character_id == char_id {
-                if let Some(handle) =
-                    play_random_sound("logout", guild_id, voicepack, manager).await
-                {
+        return;
+    }
+
+    let played = play_event(event, session, manager, &logout_handler).await;
+
+    // The session ends on logout whether or not a line played for it (it may have been muted or throttled)
+    if let Event::PlayerLogout(logout) = event {
+        if logout.character_id == session.character.character_id {
+            match played {
+                // Disconnect once the last line for the logout has played
+                Some(handle) => {
                     let _ =
                         handle.add_event(songbird::Event::Track(TrackEvent::End), logout_handler);
                 }
+                None => logout_handler.end_session().await,
             }
-            None
         }
-        // Bastion Pull: https://discord.com/channels/251073753759481856/451032574538547201/780538521492389908
-        // Currently "unlock_camo" and "enemy_bastion_pull" are not implemented
-        Event::ItemAdded(ia) => handle_item_added(ia, char_id, logout_handler).await,
-        _ => None,
+    }
+}
+
+// Plays the line for something the tracked character unlocked, and announces new battle ranks in the text channel
+pub async fn handle_unlock(
+    unlock: Unlock,
+    session: &mut Session,
+    manager: &Arc<Songbird>,
+    logout_handler: OnLogout,
+) {
+    let mut fields = Fields::new();
+    let event_name = match unlock {
+        Unlock::BattleRank {
+            battle_rank,
+            prestige_level,
+        } => {
+            let character = &session.character;
+            // Two quick rank ups can both find the second rank
+            if (prestige_level, battle_rank) <= (character.prestige_level, character.battle_rank) {
+                return;
+            }
+            let asp_rank_up = prestige_level > character.prestige_level;
+
+            let channel_id = logout_handler.channel_id;
+            let http = logout_handler.http.clone();
+            let content = if asp_rank_up {
+                format!(
+                    "{} reached ASP rank {}!",
+                    logout_handler.char_name, prestige_level
+                )
+            } else {
+                format!(
+                    "{} reached battle rank {}!",
+                    logout_handler.char_name, battle_rank
+                )
+            };
+            tokio::spawn(async move {
+                let _ = channel_id.send_message(&http, |m| m.content(content)).await;
+            });
+
+            fields.insert("battle_rank", battle_rank.into());
+            fields.insert("prestige_level", prestige_level.into());
+            fields.insert("asp_rank_up", asp_rank_up.into());
+            fields.insert("milestone", (battle_rank % 10 == 0).into());
+            session.character.battle_rank = battle_rank;
+            session.character.prestige_level = prestige_level;
+            "BattleRankUp"
+        }
+        Unlock::Achievement(finished_at) => {
+            if finished_at <= session.last_achievement {
+                return;
+            }
+            session.last_achievement = finished_at;
+            "AchievementEarned"
+        }
+        Unlock::Skill(unlocked_at) => {
+            if unlocked_at <= session.last_skill {
+                return;
+            }
+            session.last_skill = unlocked_at;
+            "SkillAdded"
+        }
+    };
+
+    let categories = session.manifest.categories(event_name, &fields);
+    play_categories(categories, None, None, session, manager, &logout_handler).await;
+}
+
+// Plays the line for an event, returning the last line it queued
+async fn play_event(
+    event: &Event,
+    session: &mut Session,
+    manager: &Arc<Songbird>,
+    logout_handler: &OnLogout,
+) -> Option<TrackHandle> {
+    let maybe_fields = {
+        let data = logout_handler.data_clone.read().await;
+        if let Some(feed) = &session.feed {
+            if let Some(entry) = feed_entry(event, session, &data) {
+                let _ = feed.send(entry);
+            }
+        }
+        event_fields(event, session, &data)
     };
-    if let Some(category) = maybe_category {
-        play_random_sound(&category, guild_id, voicepack, manager).await;
+    let (event_name, fields) = match maybe_fields {
+        Some(event_fields) => event_fields,
+        None => return None,
     };
+
+    let mut categories = session.manifest.categories(event_name, &fields);
+    // The name of the multikill tier this event reached, for TTS templates
+    let mut tier_name = None;
+    if let Event::Death(death) = event {
+        if death.attacker_character_id == session.character.character_id
+            && death.character_id != death.attacker_character_id
+        {
+            let tiers = session.spree.climb(death.timestamp, &session.manifest);
+            tier_name = tiers.first().map(|tier| tier.name.clone());
+
+            if let Some(tier) = tiers.first() {
+                if session.announced_tiers.insert(tier.kills) {
+                    // Sent in the background, so the multikill line isn't held up by Discord
+                    let channel_id = logout_handler.channel_id;
+                    let http = logout_handler.http.clone();
+                    let content = format!(
+                        "{} got their first {} of the session!",
+                        logout_handler.char_name, tier.name
+                    );
+                    tokio::spawn(async move {
+                        let _ = channel_id.send_message(&http, |m| m.content(content)).await;
+                    });
+                }
+            }
+
+            let tier_categories = tiers.iter().map(|tier| tier.category.clone());
+            categories.splice(0..0, tier_categories);
+        } else if death.character_id == session.character.character_id {
+            // Dying ends the spree, even if they're revived and keep killing within the window
+            session.spree = Spree::default();
+        }
+    }
+
+    if let Event::PlayerLogin(login) = event {
+        if login.character_id == session.character.character_id {
+            // Sent in the background, so the login line isn't held up by the Census and Discord
+            tokio::spawn(send_login_greeting(
+                session.character.clone(),
+                login.timestamp,
+                logout_handler.channel_id,
+                logout_handler.http.clone(),
+            ));
+            session.character.last_login = Some(login.timestamp);
+        }
+    }
+
+    play_categories(
+        categories,
+        Some(event),
+        tier_name,
+        session,
+        manager,
+        logout_handler,
+    )
+    .await
 }
 
-// Plays a random track from the given category in the VC, returns Option<TrackHandle> if it has successfully started
-async fn play_random_sound(
-    sound_category: &str,
-    guild_id: &u64,
-    voicepack: &String,
+// Plays the first of `categories` that has a line, returning the last line it queued. `event` is what the line is
+// for, if it's for an ESS event, to fill in TTS templates.
+async fn play_categories(
+    categories: Vec<String>,
+    event: Option<&Event>,
+    tier_name: Option<String>,
+    session: &mut Session,
     manager: &Arc<Songbird>,
+    logout_handler: &OnLogout,
 ) -> Option<TrackHandle> {
-    if let Some(handler_lock) = manager.get(*guild_id) {
-        let mut handler = handler_lock.lock().await;
-
-        let pwd = env::current_dir().expect("Could not get pwd.");
-        let pwd = pwd.display();
-        let category_path = format!("{}/voicepacks/{}/{}.txt", pwd, voicepack, sound_category);
-        let category_content = std::fs::read_to_string(category_path.clone()).expect(
-            format!(
-                "Could not read track names from category file: {}",
-                category_path
+    // Cooldowns and mutes apply to the most specific category, so e.g. a heal on cooldown doesn't fall back to
+    // something else. They're enforced here, before anything is enqueued.
+    let top_category = match categories.first() {
+        Some(category) => category.clone(),
+        None => return None,
+    };
+    *session
+        .stats
+        .categories
+        .entry(top_category.clone())
+        .or_default() += 1;
+    // Logouts end the session, so their line isn't throttled like the rest
+    let is_logout = matches!(event, Some(Event::PlayerLogout(_)));
+    if session.manifest.muted.contains(&top_category)
+        || (!is_logout
+            && session
+                .history
+                .is_throttled(&top_category, &session.manifest, Instant::now()))
+        || !playback::admit(&top_category, session, manager).await
+    {
+        return None;
+    }
+
+    // An announcer-style callout (e.g. "Triple Kill with the Gauss SAW!") for the most specific category that has one
+    let callout_template = categories
+        .iter()
+        .find_map(|category| session.manifest.tts.callouts.get(category))
+        .cloned();
+    let callout = match callout_template {
+        Some(template) if tts::is_enabled() => {
+            let values = template_values(
+                event,
+                &template,
+                tier_name.as_deref(),
+                session,
+                logout_handler,
             )
-            .as_str(),
-        );
-        let track_names = category_content.split("\n").filter(|name| *name != "");
-        // Track names file could be empty, so do nothing if None
-        let mut rng: StdRng = SeedableRng::from_entropy();
-        if let Some(random_track_name) = track_names.choose(&mut rng) {
-            let random_track_path = format!(
-                "{}/voicepacks/{}/tracks/{}",
-                pwd, voicepack, random_track_name
-            );
-            let source = match songbird::ffmpeg(random_track_path).await {
-                Ok(source) => source,
-                Err(why) => {
-                    println!("Err starting source: {:?}", why);
-                    return None;
+            .await;
+            tts::render(&template, &values)
+        }
+        _ => None,
+    };
+    let callout_mode = session.manifest.tts.callout_mode.unwrap_or_default();
+
+    let mut played = None;
+    if let (Some(text), CalloutMode::Instead) = (&callout, callout_mode) {
+        played = playback::play_tts(text, &top_category, None, session, manager).await;
+    }
+    // Also when the callout was meant to replace the track but couldn't be spoken (e.g. the TTS command failed)
+    if played.is_none() {
+        for category in &categories {
+            played = playback::play_random_sound(category, &top_category, session, manager).await;
+            if played.is_none() && tts::is_enabled() {
+                if let Some(template) = session.manifest.tts.fallback.get(category).cloned() {
+                    let values = template_values(
+                        event,
+                        &template,
+                        tier_name.as_deref(),
+                        session,
+                        logout_handler,
+                    )
+                    .await;
+                    if let Some(text) = tts::render(&template, &values) {
+                        played =
+                            playback::play_tts(&text, &top_category, None, session, manager).await;
+                    }
                 }
-            };
-            println!("Enqueueing source now");
-            Some(handler.enqueue_source(source))
-        } else {
-            None
+            }
+            if played.is_some() {
+                break;
+            }
         }
-    } else {
-        println!("Could not play source on event");
-        None
     }
+    if let (Some(text), CalloutMode::After) = (callout, callout_mode) {
+        let callout_handle =
+            playback::play_tts(&text, &top_category, played.as_ref(), session, manager).await;
+        if callout_handle.is_some() {
+            played = callout_handle;
+        }
+    }
+
+    if played.is_some() {
+        session.history.record(top_category, Instant::now());
+    }
+    played
+}
+
+// Posts the character's current stats to the session's text channel
+async fn send_login_greeting(
+    character: Character,
+    login_timestamp: DateTime<Utc>,
+    channel_id: ChannelId,
+    http: Arc<Http>,
+) {
+    let last_login = character.last_login;
+    // Refreshed for their current battle rank
+    let character = match census::get_character_by_id(character.character_id).await {
+        Ok(character) => character,
+        Err(why) => {
+            println!("Could not refresh character for login greeting: {}", why);
+            character
+        }
+    };
+
+    let description = match last_login {
+        Some(last_login) => {
+            let away = login_timestamp - last_login;
+            if away.num_days() > 0 {
+                format!("Welcome back! Last seen {} days ago.", away.num_days())
+            } else if away.num_hours() > 0 {
+                format!("Welcome back! Last seen {} hours ago.", away.num_hours())
+            } else {
+                "Welcome back!".to_string()
+            }
+        }
+        None => "Welcome!".to_string(),
+    };
+    let battle_rank = if character.prestige_level > 0 {
+        format!(
+            "{} (ASP {})",
+            character.battle_rank, character.prestige_level
+        )
+    } else {
+        character.battle_rank.to_string()
+    };
+
+    let _ = channel_id
+        .send_message(&http, |m| {
+            m.embed(|e| {
+                e.title(format!("{} logged in", character.name))
+                    .description(description)
+                    .color(character.faction_color())
+                    .field("Battle Rank", battle_rank, true)
+                    .field("Faction", character.faction_name(), true)
+                    .field("World", character.world_name(), true)
+            })
+        })
+        .await;
 }
 
 pub struct OnLogout {
@@ -207,9 +821,9 @@ pub struct OnLogout {
     pub data_clone: Arc<RwLock<TypeMap>>,
 }
 
-#[async_trait]
-impl VoiceEventHandler for OnLogout {
-    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
+impl OnLogout {
+    // Leaves the voice channel and stops listening for the character's events, which ends the session
+    pub async fn end_session(&self) {
         let _ = self
             .channel_id
             .send_message(&self.http, |m| {
@@ -227,7 +841,179 @@ impl VoiceEventHandler for OnLogout {
             .expect("Unable to get patterns in /track");
         let mut patterns = patterns.lock().await;
         patterns.remove(&self.character_id);
+    }
+}
 
+#[async_trait]
+impl VoiceEventHandler for OnLogout {
+    async fn act(&self, _: &EventContext<'_>) -> Option<songbird::Event> {
+        self.end_session().await;
         None
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::rules::tests::defaults;
+    use chrono::TimeZone;
+
+    // Parses a timestamp the way the ESS sends them, in Unix seconds
+    fn ess_timestamp(timestamp: &str) -> DateTime<Utc> {
+        Utc.timestamp_opt(timestamp.parse().unwrap(), 0)
+            .single()
+            .unwrap()
+    }
+
+    // Replays the tracked character's kills at the given ESS timestamps, returning the spree's count and the name of
+    // the highest multikill tier it reached after each kill
+    pub(crate) fn replay(
+        manifest: &Manifest,
+        timestamps: &[impl AsRef<str>],
+    ) -> Vec<(u16, Option<String>)> {
+        let mut spree = Spree::default();
+        timestamps
+            .iter()
+            .map(|timestamp| {
+                let tiers = spree.climb(ess_timestamp(timestamp.as_ref()), manifest);
+                (spree.count, tiers.first().map(|tier| tier.name.clone()))
+            })
+            .collect()
+    }
+
+    fn counts(replayed: &[(u16, Option<String>)]) -> Vec<u16> {
+        replayed.iter().map(|(count, _)| *count).collect()
+    }
+
+    // `count` timestamps `secs_apart` seconds apart, starting at `start`
+    fn every(start: i64, secs_apart: i64, count: i64) -> Vec<String> {
+        (0..count)
+            .map(|kill| (start + kill * secs_apart).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn throttle_spaces_out_low_priority_lines() {
+        let manifest = defaults();
+        let start = Instant::now();
+        let mut history = PlayHistory::default();
+        history.record("kill".to_string(), start);
+
+        // The default gap between lines is 1 second
+        let soon = start + Duration::from_millis(500);
+        assert!(history.is_throttled("kill", &manifest, soon));
+        assert!(!history.is_throttled("kill", &manifest, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn high_priority_lines_skip_the_throttle() {
+        let manifest = defaults();
+        let start = Instant::now();
+        let mut history = PlayHistory::default();
+        history.record("kill".to_string(), start);
+
+        // A double kill's line right after the kill's
+        let soon = start + Duration::from_millis(500);
+        assert!(!history.is_throttled("kill_quad", &manifest, soon));
+        assert!(!history.is_throttled("kill_penta", &manifest, soon));
+
+        // A penta after a busy minute, at the default 20 lines a minute
+        for line in 1..20 {
+            history.record("kill".to_string(), start + Duration::from_secs(line * 2));
+        }
+        let busy = start + Duration::from_secs(50);
+        assert!(history.is_throttled("kill", &manifest, busy));
+        assert!(!history.is_throttled("kill_penta", &manifest, busy));
+
+        // Lines older than a minute don't count
+        assert!(!history.is_throttled("kill", &manifest, start + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn high_priority_lines_keep_their_cooldowns() {
+        let mut manifest = defaults();
+        manifest.cooldowns.insert("kill_penta".to_string(), 30);
+        let start = Instant::now();
+        let mut history = PlayHistory::default();
+        history.record("kill_penta".to_string(), start);
+
+        let later = start + Duration::from_secs(10);
+        assert!(history.is_throttled("kill_penta", &manifest, later));
+        assert!(!history.is_throttled("kill_penta", &manifest, start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn first_kill_starts_at_one() {
+        // The spree used to be read before the kill was counted, and announced one tier too high
+        let replayed = replay(&defaults(), &["1672531200"]);
+        assert_eq!(replayed, vec![(1, None)]);
+        let replayed = replay(&defaults(), &["1672531200", "1672531201"]);
+        assert_eq!(replayed[1], (2, Some("Double Kill".to_string())));
+    }
+
+    #[test]
+    fn kill_at_window_edge_continues_spree() {
+        // The default window is 12 seconds
+        let replayed = replay(&defaults(), &["1672531200", "1672531212", "1672531224"]);
+        assert_eq!(counts(&replayed), vec![1, 2, 3]);
+        assert_eq!(replayed[2].1, Some("Triple Kill".to_string()));
+    }
+
+    #[test]
+    fn kill_past_window_starts_new_spree() {
+        let replayed = replay(&defaults(), &["1672531200", "1672531213"]);
+        assert_eq!(replayed, vec![(1, None), (1, None)]);
+        let replayed = replay(
+            &defaults(),
+            &["1672531200", "1672531205", "1672531230", "1672531231"],
+        );
+        assert_eq!(counts(&replayed), vec![1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn window_is_measured_from_the_previous_kill() {
+        let replayed = replay(&defaults(), &every(1672531200, 10, 5));
+        assert_eq!(counts(&replayed), vec![1, 2, 3, 4, 5]);
+        assert_eq!(replayed[4].1, Some("Penta Kill".to_string()));
+    }
+
+    #[test]
+    fn ten_or_more_kills_are_legendary() {
+        let replayed = replay(&defaults(), &every(1672531200, 2, 12));
+        assert_eq!(replayed[7].1, Some("Unstoppable".to_string()));
+        assert_eq!(replayed[9].1, Some("Legendary".to_string()));
+        assert_eq!(replayed[11], (12, Some("Legendary".to_string())));
+    }
+
+    #[test]
+    fn fight_climbs_and_resets_at_window_edges() {
+        // A fight's worth of kills with the timestamps the ESS would send: a few quick kills, one exactly 12 seconds
+        // after the last, then a 13 second lull before the next, which starts over
+        let replayed = replay(
+            &defaults(),
+            &[
+                "1671918271",
+                "1671918274",
+                "1671918275",
+                "1671918287",
+                "1671918291",
+                "1671918304",
+                "1671918309",
+            ],
+        );
+        let names: Vec<Option<&str>> = replayed.iter().map(|(_, name)| name.as_deref()).collect();
+        assert_eq!(counts(&replayed), vec![1, 2, 3, 4, 5, 1, 2]);
+        assert_eq!(
+            names,
+            vec![
+                None,
+                Some("Double Kill"),
+                Some("Triple Kill"),
+                Some("Quad Kill"),
+                Some("Penta Kill"),
+                None,
+                Some("Double Kill"),
+            ]
+        );
+    }
+}
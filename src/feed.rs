@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serenity::builder::CreateEmbed;
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+
+use crate::census::{self, Character};
+
+// Entries are collected for this long and posted together, so a fight doesn't hit Discord's rate limits
+const BATCH_SECS: u64 = 5;
+// Discord's limit
+const EMBEDS_PER_MESSAGE: usize = 10;
+// The color of entries where we don't know the other character's faction
+const NO_FACTION_COLOR: u32 = 0x95A5A6;
+
+pub enum FeedKind {
+    Kill,
+    Death,
+    VehicleKill,
+    VehicleLost,
+    Unlock,
+}
+
+// Something the tracked character did, to be posted in the kill feed
+pub struct FeedEntry {
+    pub kind: FeedKind,
+    // The other character involved: the victim of a kill, or the attacker for a death. None for suicides and unlocks.
+    pub other_id: Option<u64>,
+    // The weapon used, or the weapon unlocked
+    pub weapon: Option<String>,
+    pub vehicle: Option<String>,
+    pub is_headshot: bool,
+}
+
+pub type Feed = mpsc::UnboundedSender<FeedEntry>;
+
+// Starts posting the entries sent to the returned Feed in `channel_id`. The feed stops once the Feed is dropped.
+pub fn spawn(http: Arc<Http>, channel_id: ChannelId, character_name: String) -> Feed {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    tokio::task::spawn(async move {
+        // Other characters' names and factions, looked up once per session
+        let mut characters = HashMap::new();
+        let mut is_open = true;
+        while is_open {
+            let mut batch = match rx.recv().await {
+                Some(entry) => vec![entry],
+                None => break,
+            };
+            let deadline = Instant::now() + Duration::from_secs(BATCH_SECS);
+            loop {
+                match timeout_at(deadline, rx.recv()).await {
+                    Ok(Some(entry)) => batch.push(entry),
+                    Ok(None) => {
+                        is_open = false;
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+            post_batch(&http, channel_id, &character_name, batch, &mut characters).await;
+        }
+    });
+
+    tx
+}
+
+async fn post_batch(
+    http: &Http,
+    channel_id: ChannelId,
+    character_name: &str,
+    batch: Vec<FeedEntry>,
+    characters: &mut HashMap<u64, Option<Character>>,
+) {
+    let overflow = batch.len().saturating_sub(EMBEDS_PER_MESSAGE);
+    // Leave room for the embed saying how many we left out
+    let shown = if overflow > 0 {
+        EMBEDS_PER_MESSAGE - 1
+    } else {
+        batch.len()
+    };
+
+    let mut embeds = Vec::new();
+    for entry in batch.iter().take(shown) {
+        let other = match entry.other_id {
+            Some(other_id) => {
+                if !characters.contains_key(&other_id) {
+                    let character = census::get_character_by_id(other_id).await.ok();
+                    characters.insert(other_id, character);
+                }
+                characters.get(&other_id).cloned().flatten()
+            }
+            None => None,
+        };
+        embeds.push(entry_embed(entry, character_name, other.as_ref()));
+    }
+    if overflow > 0 {
+        let mut embed = CreateEmbed::default();
+        embed
+            .description(format!("...and {} more", overflow + 1))
+            .color(NO_FACTION_COLOR);
+        embeds.push(embed);
+    }
+
+    if let Err(why) = channel_id
+        .send_message(http, |m| m.set_embeds(embeds))
+        .await
+    {
+        println!("Could not post kill feed: {:?}", why);
+    }
+}
+
+fn entry_embed(entry: &FeedEntry, character_name: &str, other: Option<&Character>) -> CreateEmbed {
+    let other_name = other.map_or("someone", |other| other.name.as_str());
+    let vehicle = entry.vehicle.as_deref().unwrap_or("vehicle");
+    let title = match entry.kind {
+        FeedKind::Kill => format!("{} killed {}", character_name, other_name),
+        FeedKind::Death if entry.other_id.is_none() => format!("{} died", character_name),
+        FeedKind::Death => format!("{} killed {}", other_name, character_name),
+        FeedKind::VehicleKill => {
+            format!("{} destroyed {}'s {}", character_name, other_name, vehicle)
+        }
+        FeedKind::VehicleLost => {
+            format!("{} destroyed {}'s {}", other_name, character_name, vehicle)
+        }
+        FeedKind::Unlock => format!(
+            "{} unlocked {}",
+            character_name,
+            entry.weapon.as_deref().unwrap_or("something")
+        ),
+    };
+
+    let mut details = Vec::new();
+    if !matches!(entry.kind, FeedKind::Unlock) {
+        details.extend(entry.weapon.clone());
+    }
+    // The attacker's vehicle, if they were in one
+    if matches!(entry.kind, FeedKind::Kill | FeedKind::Death) {
+        details.extend(entry.vehicle.clone());
+    }
+    if entry.is_headshot {
+        details.push("Headshot".to_string());
+    }
+
+    let mut embed = CreateEmbed::default();
+    embed
+        .title(title)
+        .color(other.map_or(NO_FACTION_COLOR, |other| other.faction_color()));
+    if !details.is_empty() {
+        embed.description(details.join(" · "));
+    }
+    embed
+}
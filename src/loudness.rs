@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+// Normalization never boosts a quiet track by more than this, so near-silent files don't get blown up into noise
+const MAX_BOOST_DB: f64 = 10.0;
+
+// A track's measured loudness, along with what the file looked like when it was measured
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Measurement {
+    lufs: f64,
+    modified: u64,
+    len: u64,
+}
+
+fn cache_path(voicepack: &str) -> String {
+    let pwd = env::current_dir().expect("Could not get pwd.");
+    format!("{}/voicepacks/{}/loudness.json", pwd.display(), voicepack)
+}
+
+fn read_cache(cache_path: &str) -> HashMap<String, Measurement> {
+    fs::read_to_string(cache_path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+// The integrated loudness (EBU R128, in LUFS) of the voicepack's tracks that `measure_voicepack` has already measured,
+// keyed by track file name. This doesn't run ffmpeg, so it's quick enough to call when a session starts.
+pub fn cached_measurements(voicepack: &str) -> HashMap<String, f64> {
+    read_cache(&cache_path(voicepack))
+        .into_iter()
+        .map(|(track_name, measurement)| (track_name, measurement.lufs))
+        .collect()
+}
+
+// Measures the integrated loudness of every new or changed track in a voicepack, and caches the measurements in
+// `voicepacks/<voicepack>/loudness.json` for `cached_measurements`. This blocks on ffmpeg, so call it from
+// `spawn_blocking`.
+pub fn measure_voicepack(voicepack: &str) {
+    let pwd = env::current_dir().expect("Could not get pwd.");
+    let pwd = pwd.display();
+    let tracks_dir = format!("{}/voicepacks/{}/tracks", pwd, voicepack);
+    let cache_path = cache_path(voicepack);
+
+    let mut cache = read_cache(&cache_path);
+
+    let files = match fs::read_dir(&tracks_dir) {
+        Ok(files) => files,
+        Err(why) => {
+            println!("Could not read tracks dir {}: {:?}", tracks_dir, why);
+            return;
+        }
+    };
+
+    let mut changed = false;
+    let mut present = Vec::new();
+    for file in files.flatten() {
+        let track_name = file.file_name().to_string_lossy().to_string();
+        let metadata = match file.metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+        let len = metadata.len();
+        present.push(track_name.clone());
+
+        if let Some(measurement) = cache.get(&track_name) {
+            if measurement.modified == modified && measurement.len == len {
+                continue;
+            }
+        }
+
+        match measure_track(&format!("{}/{}", tracks_dir, track_name)) {
+            Ok(lufs) => {
+                cache.insert(
+                    track_name,
+                    Measurement {
+                        lufs,
+                        modified,
+                        len,
+                    },
+                );
+                changed = true;
+            }
+            Err(why) => println!("Could not measure loudness of {}: {}", track_name, why),
+        }
+    }
+
+    // Forget tracks that were removed from the voicepack
+    let before = cache.len();
+    cache.retain(|track_name, _| present.contains(track_name));
+    changed |= cache.len() != before;
+
+    if changed {
+        match serde_json::to_string_pretty(&cache) {
+            Ok(content) => {
+                if let Err(why) = fs::write(&cache_path, content) {
+                    println!("Could not write loudness cache {}: {:?}", cache_path, why);
+                }
+            }
+            Err(why) => println!("Could not serialize loudness cache: {:?}", why),
+        }
+    }
+}
+
+// Runs the file through ffmpeg's ebur128 filter and reads the integrated loudness from its summary, which looks like:
+//   Integrated loudness:
+//     I:         -19.5 LUFS
+fn measure_track(path: &str) -> Result<f64, String> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i", path])
+        .args(["-af", "ebur128", "-f", "null", "-"])
+        .output()
+        .map_err(|why| format!("Could not run ffmpeg: {}", why))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr
+        .lines()
+        .rev()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("I:")
+                .and_then(|value| value.trim().strip_suffix("LUFS"))
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        })
+        // A silent track measures as -inf, which we can't normalize (or store in the cache)
+        .filter(|lufs| lufs.is_finite())
+        .ok_or_else(|| "No integrated loudness in ffmpeg output".to_string())
+}
+
+// The gain (in dB) that brings a track measured at `lufs` to `target_lufs`
+pub fn normalization_gain(lufs: f64, target_lufs: f64) -> f64 {
+    (target_lufs - lufs).min(MAX_BOOST_DB)
+}
+
+pub fn db_to_linear(db: f64) -> f32 {
+    10f64.powf(db / 20.0) as f32
+}
@@ -1,15 +1,28 @@
+mod census;
+mod chart;
 mod commands;
 mod events;
+mod feed;
+mod loudness;
+mod playback;
+mod repo;
+mod rules;
+mod stats;
+mod track_cache;
+mod tts;
+mod unlocks;
 
-use auraxis::api::client::{ApiClient, ApiClientConfig};
 use auraxis::realtime::subscription::SubscriptionSettings;
 use auraxis::realtime::Service;
 use auraxis::realtime::{
     client::{RealtimeClient, RealtimeClientConfig},
     event::Event,
 };
+use census::{Vehicle, Weapon};
 use dotenv::dotenv;
+use repo::Repo;
 use serenity::async_trait;
+use serenity::builder::CreateEmbed;
 use serenity::model::application::interaction::{Interaction, InteractionResponseType};
 use serenity::model::gateway::Ready;
 use serenity::model::id::GuildId;
@@ -17,11 +30,12 @@ use serenity::model::prelude::command::Command;
 use serenity::model::prelude::Activity;
 use serenity::prelude::*;
 use songbird::SerenityInit;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tokio::task;
+use track_cache::{SharedTrackCache, TrackCache};
 
 struct Handler;
 
@@ -29,6 +43,8 @@ struct Handler;
 pub enum CommandResponse {
     Message(String),
     EditMessage(String),
+    Embed(CreateEmbed),
+    EditEmbed(CreateEmbed),
 }
 
 #[async_trait]
@@ -38,6 +54,13 @@ impl EventHandler for Handler {
             let command_response = match command.data.name.as_str() {
                 "ping" => commands::ping::run(&command.data.options),
                 "track" => commands::track::run(&command, &ctx, &command.data.options).await,
+                "volume" => commands::volume::run(&command, &ctx, &command.data.options).await,
+                "stats" => commands::stats::run(&command, &ctx, &command.data.options).await,
+                "link" => commands::link::run(&command, &ctx, &command.data.options).await,
+                "config" => commands::config::run(&command, &ctx, &command.data.options).await,
+                "leaderboard" => {
+                    commands::leaderboard::run(&command, &ctx, &command.data.options).await
+                }
                 _ => CommandResponse::Message("not implemented :(".to_string()),
             };
 
@@ -64,6 +87,28 @@ impl EventHandler for Handler {
                         println!("Cannot edit response to slash command: {}", why);
                     }
                 }
+                CommandResponse::Embed(embed) => {
+                    if let Err(why) = command
+                        .create_interaction_response(&ctx.http, |response| {
+                            response
+                                .kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|message| message.set_embed(embed))
+                        })
+                        .await
+                    {
+                        println!("Cannot respond to slash command: {}", why);
+                    }
+                }
+                CommandResponse::EditEmbed(embed) => {
+                    if let Err(why) = command
+                        .edit_original_interaction_response(&ctx.http, |response| {
+                            response.set_embed(embed)
+                        })
+                        .await
+                    {
+                        println!("Cannot edit response to slash command: {}", why);
+                    }
+                }
             }
         }
     }
@@ -94,6 +139,11 @@ impl EventHandler for Handler {
                 commands
                     .create_application_command(|command| commands::ping::register(command))
                     .create_application_command(|command| commands::track::register(command))
+                    .create_application_command(|command| commands::volume::register(command))
+                    .create_application_command(|command| commands::stats::register(command))
+                    .create_application_command(|command| commands::leaderboard::register(command))
+                    .create_application_command(|command| commands::config::register(command))
+                    .create_application_command(|command| commands::link::register(command))
             })
             .await
         } else {
@@ -101,6 +151,11 @@ impl EventHandler for Handler {
                 commands
                     .create_application_command(|command| commands::ping::register(command))
                     .create_application_command(|command| commands::track::register(command))
+                    .create_application_command(|command| commands::volume::register(command))
+                    .create_application_command(|command| commands::stats::register(command))
+                    .create_application_command(|command| commands::leaderboard::register(command))
+                    .create_application_command(|command| commands::config::register(command))
+                    .create_application_command(|command| commands::link::register(command))
             })
             .await
         };
@@ -118,7 +173,7 @@ impl EventHandler for Handler {
     }
 }
 
-pub async fn init_ess(event_patterns: Arc<Mutex<HashMap<u64, Sender<Event>>>>) -> RealtimeClient {
+pub async fn init_ess(event_patterns: Arc<Mutex<HashMap<u64, SessionSender>>>) -> RealtimeClient {
     let sid = env::var("SERVICE_ID").expect("Expected a service ID in the environment");
 
     let config = RealtimeClientConfig {
@@ -152,24 +207,61 @@ pub async fn init_ess(event_patterns: Arc<Mutex<HashMap<u64, Sender<Event>>>>) -
     client
 }
 
-async fn handle_event(event: Event, event_patterns: &Arc<Mutex<HashMap<u64, Sender<Event>>>>) {
+async fn handle_event(event: Event, event_patterns: &Arc<Mutex<HashMap<u64, SessionSender>>>) {
     let patterns = event_patterns.lock().await;
-    let character_ids = vec![
+
+    // World events aren't tied to a character, so they go to every session on the event's world
+    if let Some(world_id) = get_world_id(&event) {
+        for session in patterns.values() {
+            if session.world_id != world_id {
+                continue;
+            }
+            if let Err(why) = session.tx.send(event.clone()).await {
+                eprintln!("Unable to send event for processing: {:?}", why);
+            }
+        }
+        return;
+    }
+
+    // Only tracked characters' unlocks are subscribed to, but these don't say whose they are, so every session gets
+    // them and checks the Census for its own character
+    if let Event::BattleRankUp | Event::AchievementEarned | Event::SkillAdded = event {
+        for session in patterns.values() {
+            if let Err(why) = session.tx.send(event.clone()).await {
+                eprintln!("Unable to send event for processing: {:?}", why);
+            }
+        }
+        return;
+    }
+
+    // A set, since suicides (and the like) have the same character as the victim and the attacker, and the session
+    // should only get the event once
+    let character_ids: HashSet<u64> = [
         get_character_id(&event),
         get_attacker_id(&event),
         get_other_id(&event),
-    ];
-    for id in character_ids {
-        if let Some(character_id) = id {
-            if let Some(tx) = patterns.get(&character_id) {
-                if let Err(why) = tx.send(event.clone()).await {
-                    eprintln!("Unable to send event for processing: {:?}", why);
-                }
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    for character_id in character_ids {
+        if let Some(session) = patterns.get(&character_id) {
+            if let Err(why) = session.tx.send(event.clone()).await {
+                eprintln!("Unable to send event for processing: {:?}", why);
             }
         }
     }
 }
 
+fn get_world_id(event: &Event) -> Option<u64> {
+    match event {
+        Event::MetagameEvent(me) => Some(me.world_id),
+        Event::ContinentLock(lock) => Some(lock.world_id),
+        Event::ContinentUnlock(unlock) => Some(unlock.world_id),
+        _ => None,
+    }
+}
+
 fn get_character_id(event: &Event) -> Option<u64> {
     match event {
         Event::PlayerLogin(login) => Some(login.character_id),
@@ -180,6 +272,8 @@ fn get_character_id(event: &Event) -> Option<u64> {
         Event::PlayerFacilityCapture(pfc) => Some(pfc.character_id),
         Event::PlayerFacilityDefend(pfd) => Some(pfd.character_id),
         Event::ItemAdded(ia) => Some(ia.character_id),
+        // The auraxis branch we're pinned to doesn't deserialize a payload for these three, so they're sent to
+        // every session instead (see `handle_event`)
         Event::AchievementEarned => None,
         Event::SkillAdded => None,
         Event::BattleRankUp => None,
@@ -202,49 +296,22 @@ fn get_other_id(event: &Event) -> Option<u64> {
     }
 }
 
-// const WEAPON_ID_URL: &str = "https://census.lithafalcon.cc/get/ps2/item?code_factory_name=Weapon&c:show=item_id&c:limit=5000";
-async fn get_weapon_ids() -> Vec<u64> {
-    // Fetch character
-    let sid = env::var("SERVICE_ID").expect("Expected a service ID in the environment");
-    let mut client_config = ApiClientConfig::default();
-    client_config.service_id = Some(sid);
-    client_config.api_url = Some(String::from("https://census.lithafalcon.cc"));
-    client_config.environment = Some(String::from("ps2"));
-
-    let client = ApiClient::new(client_config);
-
-    let query = client
-        .get("item")
-        .limit(5000)
-        .show("item_id")
-        .filter(
-            "code_factory_name",
-            auraxis::api::request::FilterType::EqualTo,
-            "Weapon",
-        )
-        .build();
-
-    match query.await {
-        Ok(response) => response
-            .items
-            .iter()
-            .map(|val| {
-                val.get("item_id")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .parse::<u64>()
-                    .unwrap()
-            })
-            .collect::<Vec<u64>>(),
-        Err(err) => panic!("Could not query Sanctuary Census for weapon IDs: {}", err),
-    }
+struct Weapons;
+
+impl TypeMapKey for Weapons {
+    type Value = Arc<HashMap<u64, Weapon>>;
 }
 
-struct WeaponIds;
+struct Vehicles;
 
-impl TypeMapKey for WeaponIds {
-    type Value = Arc<Vec<u64>>;
+impl TypeMapKey for Vehicles {
+    type Value = Arc<HashMap<u64, Vehicle>>;
+}
+
+struct FacilityTypes;
+
+impl TypeMapKey for FacilityTypes {
+    type Value = Arc<HashMap<u64, String>>;
 }
 
 struct ESSClient;
@@ -253,10 +320,35 @@ impl TypeMapKey for ESSClient {
     type Value = RealtimeClient;
 }
 
+// Where a tracked character's events are sent, and the world they're on (for world events)
+pub struct SessionSender {
+    pub world_id: u64,
+    pub tx: Sender<Event>,
+}
+
 struct EventPatterns;
 
 impl TypeMapKey for EventPatterns {
-    type Value = Arc<Mutex<HashMap<u64, Sender<Event>>>>;
+    type Value = Arc<Mutex<HashMap<u64, SessionSender>>>;
+}
+
+// Each guild's volume (1.0 being the tracks' own volume), set with /volume
+struct Volumes;
+
+impl TypeMapKey for Volumes {
+    type Value = Arc<Mutex<HashMap<u64, f32>>>;
+}
+
+struct CachedTracks;
+
+impl TypeMapKey for CachedTracks {
+    type Value = SharedTrackCache;
+}
+
+struct Database;
+
+impl TypeMapKey for Database {
+    type Value = Repo;
 }
 
 #[tokio::main]
@@ -285,16 +377,29 @@ async fn main() {
         .await
         .expect("Could not initialize ESS client");
 
-    let weapon_ids = get_weapon_ids().await;
+    let weapons = census::get_weapons().await;
+    let vehicles = census::get_vehicles().await;
+    let facility_types = census::get_facility_types().await;
+    let track_cache = Arc::new(Mutex::new(TrackCache::new()));
+    let pwd = env::current_dir().expect("Could not get pwd.");
+    let repo = Repo::open(&pwd.join("data/blurber.db")).expect("Could not open the database");
 
     // Put our ESS client/RealtimeClient and event patterns in the client data.
     {
         let mut data = client.data.write().await;
         data.insert::<ESSClient>(ess_client);
         data.insert::<EventPatterns>(data_event_patterns);
-        data.insert::<WeaponIds>(Arc::new(weapon_ids));
+        data.insert::<Weapons>(Arc::new(weapons));
+        data.insert::<Vehicles>(Arc::new(vehicles));
+        data.insert::<FacilityTypes>(Arc::new(facility_types));
+        data.insert::<Volumes>(Arc::new(Mutex::new(HashMap::new())));
+        data.insert::<CachedTracks>(track_cache.clone());
+        data.insert::<Database>(repo);
     }
 
+    // Decoding every track takes a while, so don't hold up the bot for it
+    task::spawn(track_cache::preload(track_cache));
+
     // Finally, start a single shard, and start listening to events.
     //
     // Shards will automatically attempt to reconnect, and will perform
@@ -0,0 +1,521 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serenity::async_trait;
+use serenity::prelude::TypeMapKey;
+use songbird::input::Input;
+use songbird::tracks::{create_player, TrackHandle};
+use songbird::EventHandler as VoiceEventHandler;
+use songbird::{Call, Event, EventContext, Songbird, TrackEvent};
+
+use crate::events::Session;
+use crate::rules::{Manifest, Playback, SelectionMode};
+use crate::{track_cache, tts};
+
+// A track's volume relative to the session's volume, stored in its TrackHandle's typemap
+pub struct TrackGain;
+
+impl TypeMapKey for TrackGain {
+    type Value = f32;
+}
+
+// A line we've enqueued in the session's call, kept so the playback policy can drop or reorder it later
+pub struct Line {
+    handle: TrackHandle,
+    category: String,
+    priority: i32,
+    enqueued_at: Instant,
+}
+
+// Whether a line with `priority` that's waited this long is stale, and should be dropped rather than played late
+fn is_stale(policy: &Playback, priority: i32, waited: Duration) -> bool {
+    priority < policy.jump_priority() && waited > policy.max_age()
+}
+
+// Whether a new line for `category` replaces a line for `waiting_category` that's still waiting to play. A newer kill
+// line is at least as high on the multikill ladder, so it replaces any kill lines still waiting.
+fn replaces(manifest: &Manifest, category: &str, waiting_category: &str) -> bool {
+    manifest.playback.coalesce_kills()
+        && manifest.is_kill_category(category)
+        && manifest.is_kill_category(waiting_category)
+}
+
+// Whether a new line with `priority` can be enqueued while `waiting` lines are waiting to play
+fn has_room(policy: &Playback, priority: i32, waiting: usize) -> bool {
+    priority >= policy.jump_priority() || waiting < policy.max_queue()
+}
+
+// Skips a low priority line that's gone stale by the time it's about to play. `admit` drops stale lines too, but only
+// when a new event comes in, so without this the end of a fight's queue would still play after it's over.
+struct SkipIfStale {
+    policy: Playback,
+    priority: i32,
+    enqueued_at: Instant,
+}
+
+#[async_trait]
+impl VoiceEventHandler for SkipIfStale {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        if let EventContext::Track(tracks) = ctx {
+            if is_stale(&self.policy, self.priority, self.enqueued_at.elapsed()) {
+                for (_, handle) in tracks.iter() {
+                    let _ = handle.stop();
+                }
+            }
+        }
+        // Only the first time it plays matters
+        Some(Event::Cancel)
+    }
+}
+
+// Stops and removes the waiting (i.e. not currently playing) lines that `should_remove` picks from the call's queue
+fn remove_waiting(handler: &Call, lines: &mut Vec<Line>, should_remove: impl Fn(&Line) -> bool) {
+    let current = handler.queue().current().map(|handle| handle.uuid());
+    let removed: Vec<_> = lines
+        .iter()
+        .filter(|line| Some(line.handle.uuid()) != current && should_remove(line))
+        .map(|line| line.handle.uuid())
+        .collect();
+    if removed.is_empty() {
+        return;
+    }
+
+    handler.queue().modify_queue(|queue| {
+        queue.retain(|queued| {
+            let handle = queued.handle();
+            if removed.contains(&handle.uuid()) {
+                let _ = handle.stop();
+                false
+            } else {
+                true
+            }
+        })
+    });
+    lines.retain(|line| !removed.contains(&line.handle.uuid()));
+}
+
+// Applies the playback policy before a line for `category` is enqueued: forgets lines that have finished, drops stale
+// low priority ones, coalesces queued kill lines, and returns whether there's room for the new line.
+pub async fn admit(category: &str, session: &mut Session, manager: &Arc<Songbird>) -> bool {
+    let handler_lock = match manager.get(session.guild_id) {
+        Some(handler_lock) => handler_lock,
+        None => return false,
+    };
+    let handler = handler_lock.lock().await;
+    let policy = &session.manifest.playback;
+    let priority = policy.priority(category);
+
+    let queued: Vec<_> = handler
+        .queue()
+        .current_queue()
+        .iter()
+        .map(|handle| handle.uuid())
+        .collect();
+    session
+        .lines
+        .retain(|line| queued.contains(&line.handle.uuid()));
+
+    remove_waiting(&handler, &mut session.lines, |line| {
+        is_stale(policy, line.priority, line.enqueued_at.elapsed())
+    });
+
+    let manifest = &session.manifest;
+    remove_waiting(&handler, &mut session.lines, |line| {
+        replaces(manifest, category, &line.category)
+    });
+
+    let waiting = handler.queue().len().saturating_sub(1);
+    has_room(policy, priority, waiting)
+}
+
+// Moves a freshly enqueued line ahead of the others (or cuts off the current line) if its priority calls for it
+fn prioritize(handler: &Call, handle: &TrackHandle, priority: i32, session: &Session) {
+    let policy = &session.manifest.playback;
+    // If ours is the only line, it's already playing
+    if priority < policy.jump_priority() || handler.queue().len() < 2 {
+        return;
+    }
+
+    let uuid = handle.uuid();
+    handler.queue().modify_queue(|queue| {
+        if let Some(index) = queue
+            .iter()
+            .position(|queued| queued.handle().uuid() == uuid)
+        {
+            if let Some(queued) = queue.remove(index) {
+                queue.insert(1, queued);
+            }
+        }
+    });
+
+    if priority >= policy.interrupt_priority() {
+        let _ = handler.queue().skip();
+    }
+}
+
+// Moves a freshly enqueued line to right after `after`, e.g. so a callout follows the clip it goes with
+fn place_after(handler: &Call, handle: &TrackHandle, after: &TrackHandle) {
+    let (uuid, after_uuid) = (handle.uuid(), after.uuid());
+    handler.queue().modify_queue(|queue| {
+        let index = queue
+            .iter()
+            .position(|queued| queued.handle().uuid() == uuid);
+        let queued = match index.and_then(|index| queue.remove(index)) {
+            Some(queued) => queued,
+            None => return,
+        };
+        match queue
+            .iter()
+            .position(|queued| queued.handle().uuid() == after_uuid)
+        {
+            Some(after_index) => queue.insert(after_index + 1, queued),
+            None => queue.push_back(queued),
+        }
+    });
+}
+
+// How a session picks the next track in a category, so the same line doesn't play twice in a row
+#[derive(Default)]
+pub struct TrackPicker {
+    // tracks left to play before any repeats, for shuffle selection
+    bag: Vec<String>,
+    // the most recently played tracks, oldest first
+    recent: VecDeque<String>,
+}
+
+impl TrackPicker {
+    // Picks which of the category's tracks (`track_names`) to play next
+    fn pick(
+        &mut self,
+        track_names: &[String],
+        manifest: &Manifest,
+        rng: &mut impl Rng,
+    ) -> Option<String> {
+        let track_name = match manifest.selection.mode.unwrap_or_default() {
+            SelectionMode::Shuffle => {
+                let bag = &mut self.bag;
+                // The category file may have changed since we filled the bag
+                bag.retain(|name| track_names.contains(name));
+                if bag.is_empty() {
+                    bag.extend(track_names.iter().cloned());
+                    bag.shuffle(rng);
+                    // The bag is drawn from the back, so don't start the new round with the last round's final track
+                    if bag.len() > 1 && bag.last() == self.recent.back() {
+                        bag.swap(0, bag.len() - 1);
+                    }
+                }
+                bag.pop()?
+            }
+            SelectionMode::Weighted => {
+                // Always leave at least one track to pick from
+                let skip = manifest
+                    .selection
+                    .recent
+                    .unwrap_or(1)
+                    .min(track_names.len().saturating_sub(1));
+                let recent = &self.recent;
+                let candidates: Vec<&String> = track_names
+                    .iter()
+                    .filter(|name| !recent.iter().rev().take(skip).any(|r| r == *name))
+                    .collect();
+                candidates
+                    .choose_weighted(rng, |name| manifest.track_weight(name))
+                    .ok()
+                    .map(|name| name.to_string())?
+            }
+        };
+
+        self.recent.push_back(track_name.clone());
+        if self.recent.len() > track_names.len() {
+            self.recent.pop_front();
+        }
+        Some(track_name)
+    }
+}
+
+// Plays a random track from the given category in the VC, returns Option<TrackHandle> if it has successfully started.
+// `priority_category` is the category the event was for, which may be more specific than the one we fell back to.
+pub async fn play_random_sound(
+    sound_category: &str,
+    priority_category: &str,
+    session: &mut Session,
+    manager: &Arc<Songbird>,
+) -> Option<TrackHandle> {
+    let pwd = env::current_dir().expect("Could not get pwd.");
+    let pwd = pwd.display();
+    let category_path = format!(
+        "{}/voicepacks/{}/{}.txt",
+        pwd, session.voicepack, sound_category
+    );
+    // Categories from a rule might not exist in every voicepack, so treat a missing file like an empty one
+    let category_content = match std::fs::read_to_string(category_path.clone()) {
+        Ok(content) => content,
+        Err(why) => {
+            println!(
+                "Could not read track names from category file {}: {:?}",
+                category_path, why
+            );
+            return None;
+        }
+    };
+    let track_names: Vec<String> = category_content
+        .split("\n")
+        .filter(|name| *name != "")
+        .map(|name| name.to_string())
+        .collect();
+    // Track names file could be empty, so do nothing if None
+    let mut rng: StdRng = SeedableRng::from_entropy();
+    let random_track_name = session
+        .pickers
+        .entry(sound_category.to_string())
+        .or_default()
+        .pick(&track_names, &session.manifest, &mut rng)?;
+    let random_track_path = format!(
+        "{}/voicepacks/{}/tracks/{}",
+        pwd, session.voicepack, random_track_name
+    );
+    let gain = session.track_gain(&random_track_name);
+    let source = match track_cache::input(&session.track_cache, &random_track_path).await {
+        Ok(source) => source,
+        Err(why) => {
+            println!("{}", why);
+            return None;
+        }
+    };
+    enqueue(source, gain, priority_category, None, session, manager).await
+}
+
+// Speaks `text` in the VC with the TTS engine, returns Option<TrackHandle> if it has successfully started. If `after`
+// is given, the line is placed right behind that one in the queue instead of by its own priority.
+pub async fn play_tts(
+    text: &str,
+    priority_category: &str,
+    after: Option<&TrackHandle>,
+    session: &mut Session,
+    manager: &Arc<Songbird>,
+) -> Option<TrackHandle> {
+    let owned_text = text.to_string();
+    let path = match tokio::task::spawn_blocking(move || tts::synthesize(&owned_text)).await {
+        Ok(Ok(path)) => path,
+        Ok(Err(why)) => {
+            println!("Could not synthesize \"{}\": {}", text, why);
+            return None;
+        }
+        Err(why) => {
+            println!("Could not wait for TTS: {:?}", why);
+            return None;
+        }
+    };
+    // Not through the track cache: every line is a new file that's unlikely to be played again, and caching it would
+    // only push voicepack tracks out
+    let source = match songbird::ffmpeg(&path).await {
+        Ok(source) => source,
+        Err(why) => {
+            println!("Err starting source: {:?}", why);
+            return None;
+        }
+    };
+    enqueue(source, 1.0, priority_category, after, session, manager).await
+}
+
+// Adds `source` to the call's queue, at the session's volume scaled by `gain`, and applies the
+// playback policy's priority for `priority_category` (or places it right after `after`)
+async fn enqueue(
+    source: Input,
+    gain: f32,
+    priority_category: &str,
+    after: Option<&TrackHandle>,
+    session: &mut Session,
+    manager: &Arc<Songbird>,
+) -> Option<TrackHandle> {
+    let handler_lock = match manager.get(session.guild_id) {
+        Some(handler_lock) => handler_lock,
+        None => {
+            println!("Could not play source on event");
+            return None;
+        }
+    };
+    let mut handler = handler_lock.lock().await;
+
+    let (mut track, handle) = create_player(source);
+    track.set_volume(session.volume().await * gain);
+    // Kept with the track so /volume can rescale it without undoing its normalization
+    handle.typemap().write().await.insert::<TrackGain>(gain);
+
+    println!("Enqueueing source now");
+    handler.enqueue(track);
+
+    let policy = &session.manifest.playback;
+    let priority = policy.priority(priority_category);
+    let enqueued_at = Instant::now();
+    if priority < policy.jump_priority() {
+        let _ = handle.add_event(
+            Event::Track(TrackEvent::Play),
+            SkipIfStale {
+                policy: policy.clone(),
+                priority,
+                enqueued_at,
+            },
+        );
+    }
+    match after {
+        Some(after) => place_after(&handler, &handle, after),
+        None => prioritize(&handler, &handle, priority, session),
+    }
+    session.lines.push(Line {
+        handle: handle.clone(),
+        category: priority_category.to_string(),
+        priority,
+        enqueued_at,
+    });
+
+    Some(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::tests::defaults;
+    use std::collections::HashSet;
+
+    // Enough seeds that a bad shuffle would show up
+    const SEEDS: u64 = 200;
+
+    fn tracks(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|track| format!("track_{}.mp4", track))
+            .collect()
+    }
+
+    fn picks(manifest: &Manifest, track_names: &[String], count: usize, seed: u64) -> Vec<String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut picker = TrackPicker::default();
+        (0..count)
+            .map(|_| picker.pick(track_names, manifest, &mut rng).unwrap())
+            .collect()
+    }
+
+    fn weighted(recent: usize) -> Manifest {
+        let mut manifest = defaults();
+        manifest.selection.mode = Some(SelectionMode::Weighted);
+        manifest.selection.recent = Some(recent);
+        manifest
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once_per_round() {
+        let track_names = tracks(4);
+        for seed in 0..SEEDS {
+            for round in picks(&defaults(), &track_names, 12, seed).chunks(4) {
+                let played: HashSet<&String> = round.iter().collect();
+                assert_eq!(played.len(), 4, "seed {}: {:?}", seed, round);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_never_starts_a_round_with_the_last_rounds_final_track() {
+        let track_names = tracks(3);
+        for seed in 0..SEEDS {
+            let picked = picks(&defaults(), &track_names, 30, seed);
+            for pair in picked.windows(2) {
+                assert_ne!(pair[0], pair[1], "seed {}: {:?}", seed, picked);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_with_one_track_repeats_it() {
+        let track_names = tracks(1);
+        assert_eq!(
+            picks(&defaults(), &track_names, 3, 0),
+            vec![track_names[0].clone(); 3]
+        );
+    }
+
+    #[test]
+    fn weighted_skips_recent_tracks() {
+        let track_names = tracks(3);
+        for seed in 0..SEEDS {
+            let picked = picks(&weighted(2), &track_names, 20, seed);
+            for window in picked.windows(3) {
+                let played: HashSet<&String> = window.iter().collect();
+                assert_eq!(played.len(), 3, "seed {}: {:?}", seed, picked);
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_always_leaves_a_candidate() {
+        // Skipping the one track would leave nothing to play
+        let track_names = tracks(1);
+        assert_eq!(
+            picks(&weighted(1), &track_names, 3, 0),
+            vec![track_names[0].clone(); 3]
+        );
+        // Skipping more tracks than there are still leaves one
+        let track_names = tracks(2);
+        for seed in 0..SEEDS {
+            let picked = picks(&weighted(5), &track_names, 10, seed);
+            for pair in picked.windows(2) {
+                assert_ne!(pair[0], pair[1], "seed {}: {:?}", seed, picked);
+            }
+        }
+    }
+
+    #[test]
+    fn low_priority_lines_go_stale_after_max_age() {
+        // By default, lines below 50 are dropped after waiting 10 seconds
+        let policy = defaults().playback;
+        let kill = policy.priority("kill");
+        assert!(!is_stale(&policy, kill, Duration::from_secs(10)));
+        assert!(is_stale(&policy, kill, Duration::from_millis(10_001)));
+    }
+
+    #[test]
+    fn high_priority_lines_never_go_stale() {
+        let policy = defaults().playback;
+        assert!(!is_stale(
+            &policy,
+            policy.priority("death"),
+            Duration::from_secs(60)
+        ));
+        assert!(!is_stale(
+            &policy,
+            policy.priority("kill_penta"),
+            Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn kill_lines_replace_waiting_kill_lines() {
+        let manifest = defaults();
+        assert!(replaces(&manifest, "kill_triple", "kill_double"));
+        assert!(replaces(&manifest, "kill_headshot", "kill"));
+        assert!(!replaces(&manifest, "kill", "revive_teammate"));
+        assert!(!replaces(&manifest, "revive_teammate", "kill"));
+    }
+
+    #[test]
+    fn kill_lines_queue_up_without_coalescing() {
+        let mut manifest = defaults();
+        manifest.playback.coalesce_kills = Some(false);
+        assert!(!replaces(&manifest, "kill_triple", "kill_double"));
+    }
+
+    #[test]
+    fn full_queue_only_admits_high_priority_lines() {
+        // By default, up to 3 lines can wait
+        let policy = defaults().playback;
+        let kill = policy.priority("kill");
+        assert!(has_room(&policy, kill, 2));
+        assert!(!has_room(&policy, kill, 3));
+        assert!(has_room(&policy, policy.priority("death"), 3));
+        assert!(has_room(&policy, policy.priority("kill_penta"), 10));
+    }
+}
@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::events::Session;
+
+// Applied in order, each one once. The database's `user_version` is how many have been applied.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_init.sql"),
+    include_str!("../migrations/0002_guild_defaults.sql"),
+    include_str!("../migrations/0003_linked_characters.sql"),
+];
+
+// Sprees of at least this many kills count as pentas
+const PENTA_KILLS: u16 = 5;
+
+#[derive(Clone, Debug, Default)]
+pub struct GuildSettings {
+    pub guild_id: u64,
+    pub default_voicepack: Option<String>,
+    pub timeout_mins: Option<u32>,
+    pub volume: Option<f32>,
+    // Roles allowed to use /track. Empty means everyone.
+    pub allowed_roles: Vec<u64>,
+    pub feed_channel: Option<u64>,
+    pub muted_categories: Vec<String>,
+    // Seconds between two plays of a category, by category
+    pub cooldowns: HashMap<String, u64>,
+}
+
+// Everything the bot has seen a character do, across all of their sessions
+#[derive(Clone, Debug, Default)]
+pub struct CharacterTotals {
+    pub name: String,
+    pub sessions: u32,
+    pub tracked_secs: u64,
+    pub kills: u32,
+    pub headshot_kills: u32,
+    pub deaths: u32,
+    pub best_spree: u16,
+    // sprees by the name of the multikill tier they peaked at, e.g. "Triple Kill"
+    pub multikills: HashMap<String, u32>,
+    pub pentas: u32,
+    pub bastion_pulls: u32,
+    pub vehicles_destroyed: u32,
+    pub revives_given: u32,
+    pub revives_received: u32,
+    pub unlocks: u32,
+}
+
+// A character's sessions in one guild over a window of time, added up
+#[derive(Clone, Debug, Default)]
+pub struct Standing {
+    pub name: String,
+    pub kills: u32,
+    pub deaths: u32,
+    pub best_spree: u16,
+    pub pentas: u32,
+    pub revives_given: u32,
+}
+
+// A character a Discord user has linked to themselves with /link
+#[derive(Clone, Debug)]
+pub struct LinkedCharacter {
+    pub character_id: u64,
+    pub name: String,
+}
+
+// Everything the bot keeps between restarts, in a SQLite database (`data/blurber.db` when the bot runs)
+#[derive(Clone)]
+pub struct Repo {
+    conn: Arc<Mutex<Connection>>,
+}
+
+fn db_error(why: rusqlite::Error) -> String {
+    format!("Database error: {}", why)
+}
+
+impl Repo {
+    // Opens the database at `path`, creating it (and its directory) if needed
+    pub fn open(path: &Path) -> Result<Repo, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|why| format!("Could not create data dir: {}", why))?;
+        }
+
+        Repo::from_connection(Connection::open(path).map_err(db_error)?)
+    }
+
+    // Applies any migrations the database hasn't had yet
+    pub fn from_connection(mut conn: Connection) -> Result<Repo, String> {
+        conn.pragma_update(None, "foreign_keys", true)
+            .map_err(db_error)?;
+        migrate(&mut conn).map_err(db_error)?;
+
+        Ok(Repo {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn guild_settings(&self, guild_id: u64) -> Result<GuildSettings, String> {
+        let conn = self.conn();
+        let settings = conn
+            .query_row(
+                "SELECT default_voicepack, timeout_mins, volume, allowed_roles, feed_channel
+                 FROM guild_settings WHERE guild_id = ?1",
+                params![guild_id as i64],
+                |row| {
+                    let allowed_roles: String = row.get(3)?;
+                    Ok(GuildSettings {
+                        guild_id,
+                        default_voicepack: row.get(0)?,
+                        timeout_mins: row.get(1)?,
+                        volume: row.get(2)?,
+                        allowed_roles: serde_json::from_str(&allowed_roles).unwrap_or_default(),
+                        feed_channel: row.get::<_, Option<i64>>(4)?.map(|id| id as u64),
+                        ..GuildSettings::default()
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error)?;
+        let mut settings = settings.unwrap_or(GuildSettings {
+            guild_id,
+            ..GuildSettings::default()
+        });
+
+        let mut statement = conn
+            .prepare(
+                "SELECT category FROM guild_muted_categories WHERE guild_id = ?1 ORDER BY category",
+            )
+            .map_err(db_error)?;
+        settings.muted_categories = statement
+            .query_map(params![guild_id as i64], |row| row.get(0))
+            .map_err(db_error)?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(db_error)?;
+
+        let mut statement = conn
+            .prepare("SELECT category, secs FROM guild_cooldowns WHERE guild_id = ?1")
+            .map_err(db_error)?;
+        settings.cooldowns = statement
+            .query_map(params![guild_id as i64], |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+            })
+            .map_err(db_error)?
+            .collect::<Result<HashMap<String, u64>, rusqlite::Error>>()
+            .map_err(db_error)?;
+
+        Ok(settings)
+    }
+
+    pub fn save_guild_settings(&self, settings: &GuildSettings) -> Result<(), String> {
+        let allowed_roles = serde_json::to_string(&settings.allowed_roles)
+            .map_err(|why| format!("Could not serialize allowed roles: {}", why))?;
+        let guild_id = settings.guild_id as i64;
+
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute(
+            "INSERT INTO guild_settings (guild_id, default_voicepack, timeout_mins, volume, allowed_roles, feed_channel)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (guild_id) DO UPDATE SET
+                 default_voicepack = excluded.default_voicepack,
+                 timeout_mins = excluded.timeout_mins,
+                 volume = excluded.volume,
+                 allowed_roles = excluded.allowed_roles,
+                 feed_channel = excluded.feed_channel",
+            params![
+                guild_id,
+                settings.default_voicepack,
+                settings.timeout_mins,
+                settings.volume,
+                allowed_roles,
+                settings.feed_channel.map(|id| id as i64)
+            ],
+        )
+        .map_err(db_error)?;
+
+        tx.execute(
+            "DELETE FROM guild_muted_categories WHERE guild_id = ?1",
+            params![guild_id],
+        )
+        .map_err(db_error)?;
+        for category in &settings.muted_categories {
+            tx.execute(
+                "INSERT INTO guild_muted_categories (guild_id, category) VALUES (?1, ?2)",
+                params![guild_id, category],
+            )
+            .map_err(db_error)?;
+        }
+
+        tx.execute(
+            "DELETE FROM guild_cooldowns WHERE guild_id = ?1",
+            params![guild_id],
+        )
+        .map_err(db_error)?;
+        for (category, secs) in &settings.cooldowns {
+            tx.execute(
+                "INSERT INTO guild_cooldowns (guild_id, category, secs) VALUES (?1, ?2, ?3)",
+                params![guild_id, category, *secs as i64],
+            )
+            .map_err(db_error)?;
+        }
+
+        tx.commit().map_err(db_error)
+    }
+
+    // The character name an alias stands for in a guild
+    pub fn alias(&self, guild_id: u64, alias: &str) -> Result<Option<String>, String> {
+        self.conn()
+            .query_row(
+                "SELECT character_name FROM character_aliases WHERE guild_id = ?1 AND alias = ?2",
+                params![guild_id as i64, alias.to_lowercase()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)
+    }
+
+    pub fn set_alias(
+        &self,
+        guild_id: u64,
+        alias: &str,
+        character_name: &str,
+    ) -> Result<(), String> {
+        self.conn()
+            .execute(
+                "INSERT INTO character_aliases (guild_id, alias, character_name) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (guild_id, alias) DO UPDATE SET character_name = excluded.character_name",
+                params![guild_id as i64, alias.to_lowercase(), character_name],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    pub fn remove_alias(&self, guild_id: u64, alias: &str) -> Result<bool, String> {
+        let removed = self
+            .conn()
+            .execute(
+                "DELETE FROM character_aliases WHERE guild_id = ?1 AND alias = ?2",
+                params![guild_id as i64, alias.to_lowercase()],
+            )
+            .map_err(db_error)?;
+        Ok(removed > 0)
+    }
+
+    // The user's linked characters, in the order they linked them
+    pub fn linked_characters(&self, user_id: u64) -> Result<Vec<LinkedCharacter>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(
+                "SELECT character_id, character_name FROM linked_characters
+                 WHERE user_id = ?1 ORDER BY rowid",
+            )
+            .map_err(db_error)?;
+        let linked = statement
+            .query_map(params![user_id as i64], |row| {
+                Ok(LinkedCharacter {
+                    character_id: row.get::<_, i64>(0)? as u64,
+                    name: row.get(1)?,
+                })
+            })
+            .map_err(db_error)?;
+
+        linked
+            .collect::<Result<Vec<LinkedCharacter>, rusqlite::Error>>()
+            .map_err(db_error)
+    }
+
+    pub fn link_character(&self, user_id: u64, character: &LinkedCharacter) -> Result<(), String> {
+        self.conn()
+            .execute(
+                "INSERT INTO linked_characters (user_id, character_id, character_name) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (user_id, character_id) DO UPDATE SET character_name = excluded.character_name",
+                params![
+                    user_id as i64,
+                    character.character_id as i64,
+                    character.name
+                ],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    pub fn unlink_character(&self, user_id: u64, character_id: u64) -> Result<bool, String> {
+        let removed = self
+            .conn()
+            .execute(
+                "DELETE FROM linked_characters WHERE user_id = ?1 AND character_id = ?2",
+                params![user_id as i64, character_id as i64],
+            )
+            .map_err(db_error)?;
+        Ok(removed > 0)
+    }
+
+    // Saves a finished session, along with its multikills and the categories it triggered
+    pub fn record_session(&self, session: &Session) -> Result<(), String> {
+        let stats = &session.stats;
+        let pentas = stats
+            .spree_peaks
+            .iter()
+            .filter(|(_, peak)| *peak >= PENTA_KILLS)
+            .count() as u32;
+        let mut multikills: HashMap<String, u32> = HashMap::new();
+        for (_, peak) in &stats.spree_peaks {
+            if let Some(tier) = session.manifest.multikill_tiers(*peak).first() {
+                *multikills.entry(tier.name.clone()).or_default() += 1;
+            }
+        }
+
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute(
+            "INSERT INTO sessions (
+                 character_id, character_name, guild_id, voicepack, started, ended, kills, headshot_kills,
+                 deaths, best_spree, pentas, vehicles_destroyed, revives_given, revives_received, unlocks
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            params![
+                session.character.character_id as i64,
+                session.character.name,
+                session.guild_id as i64,
+                session.voicepack,
+                stats.started,
+                Utc::now(),
+                stats.kills,
+                stats.headshot_kills,
+                stats.deaths,
+                stats.best_spree,
+                pentas,
+                stats.vehicles_destroyed,
+                stats.revives_given,
+                stats.revives_received,
+                stats.unlocks
+            ],
+        )
+        .map_err(db_error)?;
+        let session_id = tx.last_insert_rowid();
+
+        for (tier, count) in &multikills {
+            tx.execute(
+                "INSERT INTO session_multikills (session_id, tier, count) VALUES (?1, ?2, ?3)",
+                params![session_id, tier, count],
+            )
+            .map_err(db_error)?;
+        }
+        for (category, count) in &stats.categories {
+            tx.execute(
+                "INSERT INTO session_categories (session_id, category, count) VALUES (?1, ?2, ?3)",
+                params![session_id, category, count],
+            )
+            .map_err(db_error)?;
+        }
+
+        tx.commit().map_err(db_error)
+    }
+
+    // The character's totals over every session, or None if they've never been tracked
+    pub fn character_totals(&self, character_id: u64) -> Result<Option<CharacterTotals>, String> {
+        let conn = self.conn();
+        let totals = conn
+            .query_row(
+                // An aggregate without GROUP BY always gives a row, so filter out the one for no sessions
+                "SELECT * FROM (
+                     SELECT MAX(character_name), COUNT(*) AS session_count,
+                         COALESCE(SUM(strftime('%s', ended) - strftime('%s', started)), 0),
+                         SUM(kills), SUM(headshot_kills), SUM(deaths), MAX(best_spree), SUM(pentas),
+                         SUM(vehicles_destroyed), SUM(revives_given), SUM(revives_received), SUM(unlocks)
+                     FROM sessions WHERE character_id = ?1
+                 ) WHERE session_count > 0",
+                params![character_id as i64],
+                |row| {
+                    Ok(CharacterTotals {
+                        name: row.get(0)?,
+                        sessions: row.get(1)?,
+                        tracked_secs: row.get::<_, i64>(2)?.max(0) as u64,
+                        kills: row.get(3)?,
+                        headshot_kills: row.get(4)?,
+                        deaths: row.get(5)?,
+                        best_spree: row.get(6)?,
+                        pentas: row.get(7)?,
+                        vehicles_destroyed: row.get(8)?,
+                        revives_given: row.get(9)?,
+                        revives_received: row.get(10)?,
+                        unlocks: row.get(11)?,
+                        ..CharacterTotals::default()
+                    })
+                },
+            )
+            .optional()
+            .map_err(db_error)?;
+        let mut totals = match totals {
+            Some(totals) => totals,
+            None => return Ok(None),
+        };
+
+        let mut statement = conn
+            .prepare(
+                "SELECT tier, SUM(count) FROM session_multikills
+                 JOIN sessions ON sessions.id = session_multikills.session_id
+                 WHERE sessions.character_id = ?1
+                 GROUP BY tier",
+            )
+            .map_err(db_error)?;
+        let multikills = statement
+            .query_map(params![character_id as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .map_err(db_error)?;
+        for multikill in multikills {
+            let (tier, count) = multikill.map_err(db_error)?;
+            totals.multikills.insert(tier, count);
+        }
+
+        totals.bastion_pulls = conn
+            .query_row(
+                "SELECT COALESCE(SUM(count), 0) FROM session_categories
+                 JOIN sessions ON sessions.id = session_categories.session_id
+                 WHERE sessions.character_id = ?1 AND category = 'bastion_pull'",
+                params![character_id as i64],
+                |row| row.get(0),
+            )
+            .map_err(db_error)?;
+
+        Ok(Some(totals))
+    }
+
+    // Every character's sessions in the guild that ended after `since` (or ever, if None), added up
+    pub fn standings(
+        &self,
+        guild_id: u64,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Standing>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(
+                "SELECT MAX(character_name), SUM(kills), SUM(deaths), MAX(best_spree), SUM(pentas),
+                     SUM(revives_given)
+                 FROM sessions
+                 WHERE guild_id = ?1 AND (?2 IS NULL OR ended >= ?2)
+                 GROUP BY character_id",
+            )
+            .map_err(db_error)?;
+        let standings = statement
+            .query_map(params![guild_id as i64, since], |row| {
+                Ok(Standing {
+                    name: row.get(0)?,
+                    kills: row.get(1)?,
+                    deaths: row.get(2)?,
+                    best_spree: row.get(3)?,
+                    pentas: row.get(4)?,
+                    revives_given: row.get(5)?,
+                })
+            })
+            .map_err(db_error)?;
+
+        standings
+            .collect::<Result<Vec<Standing>, rusqlite::Error>>()
+            .map_err(db_error)
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+        tx.commit()?;
+        println!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn repo() -> Repo {
+        Repo::from_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1672531200 + secs, 0).unwrap()
+    }
+
+    // Saves a session the way `record_session` would, without needing a whole Session
+    fn add_session(
+        repo: &Repo,
+        character_id: u64,
+        name: &str,
+        guild_id: u64,
+        started: i64,
+        ended: i64,
+        kills: u32,
+    ) {
+        let conn = repo.conn();
+        conn.execute(
+            "INSERT INTO sessions (
+                 character_id, character_name, guild_id, voicepack, started, ended, kills, headshot_kills, deaths,
+                 best_spree, pentas, revives_given
+             ) VALUES (?1, ?2, ?3, 'crashmore', ?4, ?5, ?6, 1, 2, ?6, 0, 3)",
+            params![character_id as i64, name, guild_id as i64, at(started), at(ended), kills],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO session_multikills (session_id, tier, count) VALUES (?1, 'Double Kill', 1)",
+            params![conn.last_insert_rowid()],
+        )
+        .unwrap();
+    }
+
+    fn user_version(repo: &Repo) -> usize {
+        repo.conn()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn new_database_gets_every_migration() {
+        let repo = repo();
+        assert_eq!(user_version(&repo), MIGRATIONS.len());
+
+        let mut settings = repo.guild_settings(1).unwrap();
+        settings.feed_channel = Some(2);
+        settings.muted_categories = vec!["death".to_string()];
+        settings.cooldowns.insert("heal".to_string(), 30);
+        repo.save_guild_settings(&settings).unwrap();
+        let saved = repo.guild_settings(1).unwrap();
+        assert_eq!(saved.feed_channel, Some(2));
+        assert_eq!(saved.muted_categories, vec!["death".to_string()]);
+        assert_eq!(saved.cooldowns["heal"], 30);
+
+        let character = LinkedCharacter {
+            character_id: 3,
+            name: "Wrel".to_string(),
+        };
+        repo.link_character(4, &character).unwrap();
+        assert_eq!(repo.linked_characters(4).unwrap()[0].name, "Wrel");
+    }
+
+    #[test]
+    fn migrations_continue_from_user_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO guild_settings (guild_id, default_voicepack) VALUES (1, 'crashmore')",
+            [],
+        )
+        .unwrap();
+
+        let repo = Repo::from_connection(conn).unwrap();
+        assert_eq!(user_version(&repo), MIGRATIONS.len());
+        let settings = repo.guild_settings(1).unwrap();
+        assert_eq!(settings.default_voicepack.as_deref(), Some("crashmore"));
+        assert_eq!(settings.feed_channel, None);
+
+        // Nothing left to apply the second time around
+        migrate(&mut repo.conn()).unwrap();
+        assert_eq!(user_version(&repo), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn character_totals_add_up_sessions() {
+        let repo = repo();
+        assert!(repo.character_totals(1).unwrap().is_none());
+
+        add_session(&repo, 1, "Wrel", 10, 0, 600, 4);
+        add_session(&repo, 1, "Wrel", 20, 3600, 4200, 7);
+        add_session(&repo, 2, "Someone", 10, 0, 60, 100);
+
+        let totals = repo.character_totals(1).unwrap().unwrap();
+        assert_eq!(totals.name, "Wrel");
+        assert_eq!(totals.sessions, 2);
+        assert_eq!(totals.tracked_secs, 1200);
+        assert_eq!(totals.kills, 11);
+        assert_eq!(totals.deaths, 4);
+        assert_eq!(totals.best_spree, 7);
+        assert_eq!(totals.multikills["Double Kill"], 2);
+        assert_eq!(totals.bastion_pulls, 0);
+    }
+
+    #[test]
+    fn standings_only_count_the_guild_and_window() {
+        let repo = repo();
+        add_session(&repo, 1, "Wrel", 10, 0, 600, 4);
+        add_session(&repo, 1, "Wrel", 10, 3600, 4200, 7);
+        add_session(&repo, 2, "Someone", 10, 0, 60, 2);
+        add_session(&repo, 3, "Elsewhere", 20, 3600, 4200, 100);
+
+        let mut standings = repo.standings(10, None).unwrap();
+        standings.sort_by(|a, b| a.name.cmp(&b.name));
+        let kills: Vec<(&str, u32)> = standings
+            .iter()
+            .map(|standing| (standing.name.as_str(), standing.kills))
+            .collect();
+        assert_eq!(kills, vec![("Someone", 2), ("Wrel", 11)]);
+
+        let standings = repo.standings(10, Some(at(3600))).unwrap();
+        assert_eq!(standings.len(), 1);
+        assert_eq!(standings[0].name, "Wrel");
+        assert_eq!(standings[0].kills, 7);
+        assert_eq!(standings[0].best_spree, 7);
+        assert_eq!(standings[0].revives_given, 3);
+    }
+}
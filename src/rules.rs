@@ -0,0 +1,740 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{env, fs};
+
+use serde::Deserialize;
+
+// A value an event field can take, or a rule can compare against
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Value {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+pub type Fields = HashMap<&'static str, Value>;
+
+// The fields `event_fields()` in `events.rs` can give each event, which rules can have conditions on. Keep the two in
+// sync.
+fn event_field_names(event_name: &str) -> Option<&'static [&'static str]> {
+    let names: &'static [&'static str] = match event_name {
+        "Death" => &[
+            "self_character",
+            "self_attacker",
+            "same_character",
+            "is_headshot",
+            "attacker_weapon_id",
+            "attacker_vehicle_id",
+            "attacker_fire_mode_id",
+            "attacker_loadout_id",
+            "attacker_vehicle_type",
+            "weapon_category",
+        ],
+        "VehicleDestroy" => &[
+            "self_character",
+            "self_attacker",
+            "same_character",
+            "vehicle_id",
+            "vehicle_type",
+            "attacker_weapon_id",
+            "attacker_vehicle_id",
+        ],
+        "GainExperience" => &["self_character", "self_other", "experience_id"],
+        "ItemAdded" => &["self_character", "item_id", "context", "is_weapon"],
+        "PlayerFacilityCapture" | "PlayerFacilityDefend" => {
+            &["self_character", "facility_id", "facility_type"]
+        }
+        "PlayerLogin" => &["self_character", "first_login_today", "returning"],
+        "PlayerLogout" => &["self_character"],
+        "MetagameEvent" => &[
+            "metagame_event_id",
+            "metagame_event_state_name",
+            "zone_id",
+            "own_faction_won",
+        ],
+        "ContinentLock" => &["zone_id", "own_faction"],
+        "ContinentUnlock" => &["zone_id"],
+        // Looked up in the Census, see `handle_unlock()` in `events.rs`
+        "BattleRankUp" => &["battle_rank", "prestige_level", "asp_rank_up", "milestone"],
+        "AchievementEarned" | "SkillAdded" => &[],
+        _ => return None,
+    };
+    Some(names)
+}
+
+// What a rule requires of a single field, e.g. `is_headshot = true`, `item_id = [1, 2]` or `vehicle_id = { not = 0 }`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Condition {
+    Is(Value),
+    AnyOf(Vec<Value>),
+    Not { not: Value },
+}
+
+impl Condition {
+    fn matches(&self, field: Option<&Value>) -> bool {
+        match (self, field) {
+            (Condition::Is(expected), Some(value)) => expected == value,
+            (Condition::AnyOf(expected), Some(value)) => expected.contains(value),
+            (Condition::Not { not }, Some(value)) => not != value,
+            (_, None) => false,
+        }
+    }
+
+    fn values(&self) -> Vec<&Value> {
+        match self {
+            Condition::Is(value) => vec![value],
+            Condition::AnyOf(values) => values.iter().collect(),
+            Condition::Not { .. } => vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    // The ESS event name, e.g. "Death" or "GainExperience"
+    pub event: String,
+    pub category: String,
+    // When several rules match, higher priority categories are tried first
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub when: HashMap<String, Condition>,
+}
+
+impl Rule {
+    fn matches(&self, event_name: &str, fields: &Fields) -> bool {
+        self.event == event_name
+            && self
+                .when
+                .iter()
+                .all(|(field, condition)| condition.matches(fields.get(field.as_str())))
+    }
+}
+
+// Limits on how often a session plays anything at all, on top of the per-category cooldowns
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Throttle {
+    // Minimum seconds between any two lines
+    pub min_gap_secs: Option<u64>,
+    // Maximum lines played in any 60 second window
+    pub max_per_minute: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MultikillTier {
+    // How many kills in a row reach this tier
+    pub kills: u16,
+    pub category: String,
+    // Used when announcing the tier in the text channel, e.g. "Triple Kill"
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Multikill {
+    // A kill continues the spree if it comes within this many seconds of the previous one
+    pub window_secs: Option<u64>,
+    pub tiers: Option<Vec<MultikillTier>>,
+}
+
+// How the audio queue is managed. Every setting is optional, and without them lines simply play in order.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Playback {
+    // Lines below `jump_priority` are dropped when this many lines are already waiting
+    pub max_queue: Option<usize>,
+    // Lines below `jump_priority` that have waited longer than this are dropped
+    pub max_age_secs: Option<u64>,
+    // Lines at or above this priority are played next
+    pub jump_priority: Option<i32>,
+    // Lines at or above this priority cut off the line that's playing
+    pub interrupt_priority: Option<i32>,
+    // Replace kill lines that are still waiting with the newest one, which is the highest multikill tier
+    pub coalesce_kills: Option<bool>,
+    // Adjust every track's gain so it plays at this integrated loudness (EBU R128, in LUFS). Unset disables it.
+    pub normalize_lufs: Option<f64>,
+    // Categories not listed have priority 0
+    #[serde(default)]
+    pub priorities: HashMap<String, i32>,
+}
+
+impl Playback {
+    pub fn priority(&self, category: &str) -> i32 {
+        self.priorities.get(category).copied().unwrap_or_default()
+    }
+
+    pub fn max_queue(&self) -> usize {
+        self.max_queue.unwrap_or(usize::MAX)
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age_secs.map_or(Duration::MAX, Duration::from_secs)
+    }
+
+    pub fn jump_priority(&self) -> i32 {
+        self.jump_priority.unwrap_or(i32::MAX)
+    }
+
+    pub fn interrupt_priority(&self) -> i32 {
+        self.interrupt_priority.unwrap_or(i32::MAX)
+    }
+
+    pub fn coalesce_kills(&self) -> bool {
+        self.coalesce_kills.unwrap_or_default()
+    }
+
+    fn merge(&mut self, other: Playback) {
+        if other.max_queue.is_some() {
+            self.max_queue = other.max_queue;
+        }
+        if other.max_age_secs.is_some() {
+            self.max_age_secs = other.max_age_secs;
+        }
+        if other.jump_priority.is_some() {
+            self.jump_priority = other.jump_priority;
+        }
+        if other.interrupt_priority.is_some() {
+            self.interrupt_priority = other.interrupt_priority;
+        }
+        if other.coalesce_kills.is_some() {
+            self.coalesce_kills = other.coalesce_kills;
+        }
+        if other.normalize_lufs.is_some() {
+            self.normalize_lufs = other.normalize_lufs;
+        }
+        self.priorities.extend(other.priorities);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMode {
+    // Play every track in a category (in random order) before repeating one
+    #[default]
+    Shuffle,
+    // Pick randomly by each track's weight, skipping the `recent` most recently played tracks
+    Weighted,
+}
+
+// How the next track in a category is picked
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Selection {
+    pub mode: Option<SelectionMode>,
+    pub recent: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TrackSettings {
+    // Relative chance of being picked in weighted selection (1 if not set)
+    pub weight: Option<f64>,
+    // Added to the track's gain (after loudness normalization), e.g. -3.0 to make it a bit quieter
+    pub gain_db: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CalloutMode {
+    // Speak the callout after the category's track
+    #[default]
+    After,
+    // Speak the callout and skip the track
+    Instead,
+}
+
+// Lines spoken with TTS (when TTS_COMMAND is set), by category. See `voicepacks/defaults.toml` for the placeholders.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Tts {
+    // Spoken when the voicepack has no tracks for the category
+    #[serde(default)]
+    pub fallback: HashMap<String, String>,
+    // Announcer-style lines spoken along with the category's track
+    #[serde(default)]
+    pub callouts: HashMap<String, String>,
+    pub callout_mode: Option<CalloutMode>,
+}
+
+// The contents of `voicepacks/defaults.toml`, or a voicepack's `manifest.toml`
+#[derive(Debug, Default, Deserialize)]
+pub struct Manifest {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+    // Minimum seconds between two plays of a category
+    #[serde(default)]
+    pub cooldowns: HashMap<String, u64>,
+    // Categories that are never played
+    #[serde(default)]
+    pub muted: Vec<String>,
+    #[serde(default)]
+    pub throttle: Throttle,
+    #[serde(default)]
+    pub multikill: Multikill,
+    #[serde(default)]
+    pub playback: Playback,
+    #[serde(default)]
+    pub selection: Selection,
+    // Settings for individual tracks, by file name
+    #[serde(default)]
+    pub tracks: HashMap<String, TrackSettings>,
+    #[serde(default)]
+    pub tts: Tts,
+}
+
+impl Manifest {
+    fn read(path: &str) -> Result<Manifest, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|why| format!("Could not read manifest {}: {}", path, why))?;
+        let manifest: Manifest = toml::from_str(&content)
+            .map_err(|why| format!("Could not parse manifest {}: {}", path, why))?;
+        manifest
+            .check_rules()
+            .map_err(|why| format!("Invalid rule in manifest {}: {}", path, why))?;
+        Ok(manifest)
+    }
+
+    // A misspelled event or field would otherwise never match, and the rule would silently never play
+    fn check_rules(&self) -> Result<(), String> {
+        for rule in &self.rules {
+            let known_fields = event_field_names(&rule.event).ok_or_else(|| {
+                format!(
+                    "unknown event '{}' (for category '{}')",
+                    rule.event, rule.category
+                )
+            })?;
+            for field in rule.when.keys() {
+                if !known_fields.contains(&field.as_str()) {
+                    return Err(format!(
+                        "{} events have no field '{}' (for category '{}'). Their fields are: {}",
+                        rule.event,
+                        field,
+                        rule.category,
+                        known_fields.join(", ")
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Loads the default rules, extended by the voicepack's own manifest and then by the guild's settings in
+    // `guilds/<guild ID>.toml`. Their rules are added to the defaults' (a category matched by several rules plays if
+    // any of them match), while their other settings replace the defaults'.
+    pub fn load(voicepack: &str, guild_id: u64) -> Result<Manifest, String> {
+        let pwd = env::current_dir().expect("Could not get pwd.");
+        let pwd = pwd.display();
+
+        let mut manifest = Manifest::read(&format!("{}/voicepacks/defaults.toml", pwd))?;
+
+        for path in [
+            format!("{}/voicepacks/{}/manifest.toml", pwd, voicepack),
+            format!("{}/guilds/{}.toml", pwd, guild_id),
+        ] {
+            if fs::metadata(&path).is_ok() {
+                manifest.merge(Manifest::read(&path)?);
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    // Applies the mutes and cooldowns set with /config, which take precedence over every manifest: a cooldown set there
+    // replaces the one from `guilds/<guild ID>.toml`, the voicepack or the defaults. Mutes only ever add up, so /config
+    // unmute can't play a category that one of the manifests mutes.
+    pub fn apply_config(&mut self, muted: &[String], cooldowns: &HashMap<String, u64>) {
+        self.muted.extend(muted.iter().cloned());
+        self.cooldowns.extend(
+            cooldowns
+                .iter()
+                .map(|(category, secs)| (category.clone(), *secs)),
+        );
+    }
+
+    fn merge(&mut self, other: Manifest) {
+        self.rules.extend(other.rules);
+        self.cooldowns.extend(other.cooldowns);
+        self.muted.extend(other.muted);
+        if other.throttle.min_gap_secs.is_some() {
+            self.throttle.min_gap_secs = other.throttle.min_gap_secs;
+        }
+        if other.throttle.max_per_minute.is_some() {
+            self.throttle.max_per_minute = other.throttle.max_per_minute;
+        }
+        if other.multikill.window_secs.is_some() {
+            self.multikill.window_secs = other.multikill.window_secs;
+        }
+        if other.multikill.tiers.is_some() {
+            self.multikill.tiers = other.multikill.tiers;
+        }
+        self.playback.merge(other.playback);
+        if other.selection.mode.is_some() {
+            self.selection.mode = other.selection.mode;
+        }
+        if other.selection.recent.is_some() {
+            self.selection.recent = other.selection.recent;
+        }
+        self.tracks.extend(other.tracks);
+        self.tts.fallback.extend(other.tts.fallback);
+        self.tts.callouts.extend(other.tts.callouts);
+        if other.tts.callout_mode.is_some() {
+            self.tts.callout_mode = other.tts.callout_mode;
+        }
+    }
+
+    pub fn track_weight(&self, track_name: &str) -> f64 {
+        self.tracks
+            .get(track_name)
+            .and_then(|track| track.weight)
+            .unwrap_or(1.0)
+    }
+
+    pub fn track_gain_db(&self, track_name: &str) -> f64 {
+        self.tracks
+            .get(track_name)
+            .and_then(|track| track.gain_db)
+            .unwrap_or_default()
+    }
+
+    // The multikill tiers reached with `kills` kills in a row, highest first
+    pub fn multikill_tiers(&self, kills: u16) -> Vec<&MultikillTier> {
+        let mut tiers: Vec<&MultikillTier> = self
+            .multikill
+            .tiers
+            .iter()
+            .flatten()
+            .filter(|tier| tier.kills <= kills)
+            .collect();
+        tiers.sort_by(|a, b| b.kills.cmp(&a.kills));
+        tiers
+    }
+
+    // Kill categories are the ones coalesced by the playback policy
+    pub fn is_kill_category(&self, category: &str) -> bool {
+        category == "kill"
+            || category == "kill_headshot"
+            || self
+                .multikill
+                .tiers
+                .iter()
+                .flatten()
+                .any(|tier| tier.category == category)
+    }
+
+    // All categories whose rules match the event, highest priority first
+    pub fn categories(&self, event_name: &str, fields: &Fields) -> Vec<String> {
+        let mut matching: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(event_name, fields))
+            .collect();
+        matching.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+        let mut categories: Vec<String> = Vec::with_capacity(matching.len());
+        for rule in matching {
+            if !categories.contains(&rule.category) {
+                categories.push(rule.category.clone());
+            }
+        }
+        categories
+    }
+
+    // The GainExperience IDs referenced by the rules, so we only subscribe to the ones we'd play something for
+    pub fn experience_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.event == "GainExperience")
+            .filter_map(|rule| rule.when.get("experience_id"))
+            .flat_map(|condition| condition.values())
+            .filter_map(|value| match value {
+                Value::Int(id) => Some(*id as u64),
+                _ => None,
+            })
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::events::tests::replay;
+
+    pub(crate) fn defaults() -> Manifest {
+        toml::from_str(include_str!("../voicepacks/defaults.toml")).unwrap()
+    }
+
+    fn rule(toml: &str) -> Rule {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn fields(fields: &[(&'static str, Value)]) -> Fields {
+        fields.iter().cloned().collect()
+    }
+
+    #[test]
+    fn condition_is_parses_each_value_type() {
+        let rule = rule(
+            r#"
+            event = "Death"
+            category = "kill_headshot"
+            when = { is_headshot = true, attacker_weapon_id = 80, weapon_category = "Knife" }
+            "#,
+        );
+        assert!(matches!(
+            rule.when["is_headshot"],
+            Condition::Is(Value::Bool(true))
+        ));
+        assert!(matches!(
+            rule.when["attacker_weapon_id"],
+            Condition::Is(Value::Int(80))
+        ));
+        assert!(
+            matches!(&rule.when["weapon_category"], Condition::Is(Value::Str(s)) if s == "Knife")
+        );
+
+        let matching = fields(&[
+            ("is_headshot", true.into()),
+            ("attacker_weapon_id", 80u64.into()),
+            ("weapon_category", "Knife".into()),
+        ]);
+        assert!(rule.matches("Death", &matching));
+        assert!(!rule.matches("VehicleDestroy", &matching));
+
+        let mut not_headshot = matching.clone();
+        not_headshot.insert("is_headshot", false.into());
+        assert!(!rule.matches("Death", &not_headshot));
+    }
+
+    #[test]
+    fn condition_any_of_matches_any_listed_value() {
+        let rule = rule(
+            r#"
+            event = "GainExperience"
+            category = "revive"
+            when = { experience_id = [7, 53] }
+            "#,
+        );
+        assert!(
+            matches!(&rule.when["experience_id"], Condition::AnyOf(values) if values.len() == 2)
+        );
+        assert!(rule.matches("GainExperience", &fields(&[("experience_id", 7u64.into())])));
+        assert!(rule.matches(
+            "GainExperience",
+            &fields(&[("experience_id", 53u64.into())])
+        ));
+        assert!(!rule.matches("GainExperience", &fields(&[("experience_id", 8u64.into())])));
+    }
+
+    #[test]
+    fn condition_not_matches_any_other_value() {
+        let rule = rule(
+            r#"
+            event = "Death"
+            category = "death_by_vehicle"
+            when = { attacker_vehicle_id = { not = 0 } }
+            "#,
+        );
+        assert!(matches!(
+            rule.when["attacker_vehicle_id"],
+            Condition::Not { not: Value::Int(0) }
+        ));
+        assert!(rule.matches("Death", &fields(&[("attacker_vehicle_id", 4u64.into())])));
+        assert!(!rule.matches("Death", &fields(&[("attacker_vehicle_id", 0u64.into())])));
+    }
+
+    #[test]
+    fn conditions_on_missing_fields_never_match() {
+        let rule = rule(
+            r#"
+            event = "Death"
+            category = "suicide_own_grenade"
+            when = { weapon_category = "Grenade", attacker_vehicle_id = { not = 0 } }
+            "#,
+        );
+        assert!(!rule.matches("Death", &Fields::new()));
+    }
+
+    #[test]
+    fn default_rules_use_known_fields() {
+        assert_eq!(defaults().check_rules(), Ok(()));
+    }
+
+    #[test]
+    fn battle_rank_milestones_fall_back_to_rank_up() {
+        let rank_up = |battle_rank: u64, asp_rank_up: bool| {
+            let fields = fields(&[
+                ("battle_rank", battle_rank.into()),
+                ("prestige_level", 1u64.into()),
+                ("asp_rank_up", asp_rank_up.into()),
+                ("milestone", (battle_rank % 10 == 0).into()),
+            ]);
+            defaults().categories("BattleRankUp", &fields)
+        };
+        assert_eq!(rank_up(57, false), ["battle_rank_up"]);
+        assert_eq!(
+            rank_up(60, false),
+            ["battle_rank_milestone", "battle_rank_up"]
+        );
+        assert_eq!(
+            rank_up(100, false),
+            ["battle_rank_100", "battle_rank_milestone", "battle_rank_up"]
+        );
+        assert_eq!(rank_up(1, true), ["asp_rank", "battle_rank_up"]);
+    }
+
+    #[test]
+    fn misspelled_field_is_an_error() {
+        let manifest: Manifest = toml::from_str(
+            r#"
+            [[rule]]
+            event = "Death"
+            category = "kill_headshot"
+            when = { headshot = true }
+            "#,
+        )
+        .unwrap();
+        let why = manifest.check_rules().unwrap_err();
+        assert!(why.contains("no field 'headshot'"), "{}", why);
+    }
+
+    #[test]
+    fn unknown_event_is_an_error() {
+        let manifest: Manifest = toml::from_str(
+            r#"
+            [[rule]]
+            event = "Deaht"
+            category = "death"
+            "#,
+        )
+        .unwrap();
+        assert!(manifest.check_rules().is_err());
+    }
+
+    // Replays kills at the given times (in seconds since the first), returning the tier reached by each
+    fn tier_names(manifest: &Manifest, timestamps: &[&str]) -> Vec<Option<String>> {
+        replay(manifest, timestamps)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    fn names(names: &[Option<&str>]) -> Vec<Option<String>> {
+        names.iter().map(|name| name.map(String::from)).collect()
+    }
+
+    #[test]
+    fn single_kill_reaches_no_tier() {
+        assert!(defaults().multikill_tiers(0).is_empty());
+        assert!(defaults().multikill_tiers(1).is_empty());
+    }
+
+    #[test]
+    fn tiers_are_highest_first() {
+        let manifest = defaults();
+        let tiers: Vec<u16> = manifest
+            .multikill_tiers(4)
+            .iter()
+            .map(|tier| tier.kills)
+            .collect();
+        assert_eq!(tiers, vec![4, 3, 2]);
+    }
+
+    #[test]
+    fn pack_can_override_window_and_tiers() {
+        let mut manifest = defaults();
+        manifest.merge(
+            toml::from_str(
+                r#"
+                [multikill]
+                window_secs = 30
+                tiers = [
+                    { kills = 3, category = "kill_hat_trick", name = "Hat Trick" },
+                ]
+                "#,
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(
+            tier_names(
+                &manifest,
+                &["1672531200", "1672531225", "1672531250", "1672531275"]
+            ),
+            names(&[None, None, Some("Hat Trick"), Some("Hat Trick")])
+        );
+        assert!(manifest.is_kill_category("kill_hat_trick"));
+        assert!(!manifest.is_kill_category("kill_double"));
+    }
+
+    #[test]
+    fn pack_can_override_just_the_window() {
+        let mut manifest = defaults();
+        manifest.merge(toml::from_str("[multikill]\nwindow_secs = 5").unwrap());
+
+        assert_eq!(
+            tier_names(&manifest, &["1672531200", "1672531205", "1672531211"]),
+            names(&[None, Some("Double Kill"), None])
+        );
+    }
+
+    #[test]
+    fn config_takes_precedence_over_manifests() {
+        let mut manifest = defaults();
+        let voicepack: Manifest = toml::from_str(
+            r#"
+            muted = ["death"]
+            [cooldowns]
+            kill = 5
+            revive = 10
+            "#,
+        )
+        .unwrap();
+        let guild: Manifest = toml::from_str(
+            r#"
+            [cooldowns]
+            revive = 20
+            spawn = 30
+            "#,
+        )
+        .unwrap();
+        manifest.merge(voicepack);
+        manifest.merge(guild);
+        manifest.apply_config(
+            &["kill_headshot".to_string()],
+            &HashMap::from([("spawn".to_string(), 60), ("kill".to_string(), 0)]),
+        );
+
+        // /config beats the guild, which beats the voicepack
+        assert_eq!(manifest.cooldowns["kill"], 0);
+        assert_eq!(manifest.cooldowns["revive"], 20);
+        assert_eq!(manifest.cooldowns["spawn"], 60);
+        // Mutes from every layer add up
+        assert!(manifest.muted.contains(&"death".to_string()));
+        assert!(manifest.muted.contains(&"kill_headshot".to_string()));
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use auraxis::realtime::event::Event;
+use chrono::{DateTime, Utc};
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+
+use crate::census::{self, Weapon};
+use crate::chart;
+use crate::events::Session;
+
+// Revive, Squad Revive
+const REVIVE_EXPERIENCE_IDS: [u64; 2] = [7, 53];
+
+// What the tracked character got up to during a session, for the report posted when it ends
+pub struct SessionStats {
+    pub started: DateTime<Utc>,
+    pub kills: u32,
+    pub headshot_kills: u32,
+    pub deaths: u32,
+    pub best_spree: u16,
+    pub vehicles_destroyed: u32,
+    pub revives_given: u32,
+    pub revives_received: u32,
+    pub unlocks: u32,
+    // kills of each character, by character ID
+    pub kills_by_victim: HashMap<u64, u32>,
+    // deaths to each character, by character ID
+    pub deaths_by_attacker: HashMap<u64, u32>,
+    // kills with each weapon, by weapon name
+    pub kills_by_weapon: HashMap<String, u32>,
+    // when each kill and death happened, for the session chart
+    pub kill_times: Vec<DateTime<Utc>>,
+    pub death_times: Vec<DateTime<Utc>>,
+    // the highest kill count of each multikill spree, and when it was reached
+    pub spree_peaks: Vec<(DateTime<Utc>, u16)>,
+    // how many times each category was triggered (whether or not a line was played), e.g. "bastion_pull"
+    pub categories: HashMap<String, u32>,
+}
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats {
+            started: Utc::now(),
+            kills: 0,
+            headshot_kills: 0,
+            deaths: 0,
+            best_spree: 0,
+            vehicles_destroyed: 0,
+            revives_given: 0,
+            revives_received: 0,
+            unlocks: 0,
+            kills_by_victim: HashMap::new(),
+            deaths_by_attacker: HashMap::new(),
+            kills_by_weapon: HashMap::new(),
+            kill_times: Vec::new(),
+            death_times: Vec::new(),
+            spree_peaks: Vec::new(),
+            categories: HashMap::new(),
+        }
+    }
+
+    // Counts an event for `character_id`. `spree` is the session's kill spree after the event was handled.
+    pub fn record(
+        &mut self,
+        event: &Event,
+        character_id: u64,
+        spree: u16,
+        weapons: &HashMap<u64, Weapon>,
+    ) {
+        match event {
+            Event::Death(death) if death.character_id == character_id => {
+                self.deaths += 1;
+                self.death_times.push(death.timestamp);
+                if death.attacker_character_id != character_id && death.attacker_character_id != 0 {
+                    *self
+                        .deaths_by_attacker
+                        .entry(death.attacker_character_id)
+                        .or_default() += 1;
+                }
+            }
+            Event::Death(death) if death.attacker_character_id == character_id => {
+                self.kills += 1;
+                if death.is_headshot {
+                    self.headshot_kills += 1;
+                }
+                *self.kills_by_victim.entry(death.character_id).or_default() += 1;
+                // Not "Other", which the weapons chart already uses for the weapons past its slices
+                let weapon_name = weapons
+                    .get(&death.attacker_weapon_id)
+                    .map_or("Unknown", |weapon| weapon.name.as_str());
+                *self
+                    .kills_by_weapon
+                    .entry(weapon_name.to_string())
+                    .or_default() += 1;
+                self.kill_times.push(death.timestamp);
+
+                self.best_spree = self.best_spree.max(spree);
+                if spree >= 2 {
+                    match self.spree_peaks.last_mut() {
+                        // Still the same spree, so this is its new peak
+                        Some(peak) if spree > 2 && peak.1 == spree - 1 => {
+                            *peak = (death.timestamp, spree)
+                        }
+                        _ => self.spree_peaks.push((death.timestamp, spree)),
+                    }
+                }
+            }
+            Event::VehicleDestroy(vd)
+                if vd.attacker_character_id == character_id && vd.character_id != character_id =>
+            {
+                self.vehicles_destroyed += 1;
+            }
+            Event::GainExperience(ge) if REVIVE_EXPERIENCE_IDS.contains(&ge.experience_id) => {
+                if ge.character_id == character_id {
+                    self.revives_given += 1;
+                } else if ge.other_id == character_id {
+                    self.revives_received += 1;
+                }
+            }
+            Event::ItemAdded(ia)
+                if ia.character_id == character_id && weapons.contains_key(&ia.item_id) =>
+            {
+                self.unlocks += 1;
+            }
+            _ => (),
+        }
+    }
+
+    pub fn kdr(&self) -> f32 {
+        self.kills as f32 / self.deaths.max(1) as f32
+    }
+
+    pub fn headshot_ratio(&self) -> f32 {
+        self.headshot_kills as f32 / self.kills.max(1) as f32
+    }
+}
+
+// The character `counts` has the highest count for, and the count
+fn top(counts: &HashMap<u64, u32>) -> Option<(u64, u32)> {
+    counts
+        .iter()
+        .max_by_key(|(_, count)| **count)
+        .map(|(character_id, count)| (*character_id, *count))
+}
+
+async fn describe_top(counts: &HashMap<u64, u32>) -> String {
+    match top(counts) {
+        Some((character_id, count)) => {
+            let name = census::get_character_by_id(character_id)
+                .await
+                .map_or_else(|_| "Unknown".to_string(), |character| character.name);
+            format!("{} ({})", name, count)
+        }
+        None => "Nobody".to_string(),
+    }
+}
+
+// Posts the session report embed in `channel_id`, with a chart of the session attached
+pub async fn send_summary(session: &Session, channel_id: ChannelId, http: &Http) {
+    let stats = &session.stats;
+    let ended = Utc::now();
+    let duration = ended - stats.started;
+    let best_spree = match session.manifest.multikill_tiers(stats.best_spree).first() {
+        Some(tier) => format!("{} ({} kills)", tier.name, stats.best_spree),
+        None => stats.best_spree.to_string(),
+    };
+    let most_killed = describe_top(&stats.kills_by_victim).await;
+    let nemesis = describe_top(&stats.deaths_by_attacker).await;
+
+    let chart_name = format!("session_{}.png", session.character.character_id);
+    let chart_path = env::temp_dir().join(&chart_name);
+    let has_chart = match chart::render_session(stats, ended, &chart_path) {
+        Ok(()) => true,
+        Err(why) => {
+            println!("Could not render session chart: {}", why);
+            false
+        }
+    };
+
+    let _ = channel_id
+        .send_message(http, |m| {
+            if has_chart {
+                m.add_file(chart_path.as_path());
+            }
+            m.embed(|e| {
+                if has_chart {
+                    e.image(format!("attachment://{}", chart_name));
+                }
+                e.title(format!("{}'s session", session.character.name))
+                    .color(session.character.faction_color())
+                    .field(
+                        "Duration",
+                        format!("{}h {}m", duration.num_hours(), duration.num_minutes() % 60),
+                        true,
+                    )
+                    .field("Kills", stats.kills, true)
+                    .field("Deaths", stats.deaths, true)
+                    .field("KDR", format!("{:.2}", stats.kdr()), true)
+                    .field(
+                        "Headshots",
+                        format!("{:.0}%", stats.headshot_ratio() * 100.0),
+                        true,
+                    )
+                    .field("Best Multikill", best_spree, true)
+                    .field("Vehicles Destroyed", stats.vehicles_destroyed, true)
+                    .field(
+                        "Revives",
+                        format!(
+                            "{} given, {} received",
+                            stats.revives_given, stats.revives_received
+                        ),
+                        true,
+                    )
+                    .field("Unlocks", stats.unlocks, true)
+                    .field("Most Killed", most_killed, true)
+                    .field("Nemesis", nemesis, true)
+            })
+        })
+        .await;
+
+    if has_chart {
+        let _ = fs::remove_file(&chart_path);
+    }
+}
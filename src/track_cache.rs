@@ -0,0 +1,188 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+
+use serenity::prelude::Mutex;
+use songbird::driver::Bitrate;
+use songbird::input::cached::Compressed;
+use songbird::input::Input;
+
+// How much memory the cache may use if CACHE_MB isn't set
+const DEFAULT_CACHE_MB: usize = 256;
+// Plenty for voice lines, and about a tenth of the size of decoded audio
+const BITRATE: i32 = 64_000;
+
+// Voicepack tracks decoded and Opus-compressed in memory, so playing one doesn't have to wait on a new ffmpeg process.
+// When it's full, the least recently played tracks are dropped.
+pub struct TrackCache {
+    tracks: HashMap<String, (Compressed, usize)>,
+    // track paths, least recently played first
+    order: VecDeque<String>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+pub type SharedTrackCache = Arc<Mutex<TrackCache>>;
+
+impl TrackCache {
+    pub fn new() -> TrackCache {
+        let max_mb = env::var("CACHE_MB")
+            .ok()
+            .and_then(|mb| mb.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_MB);
+
+        TrackCache {
+            tracks: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_bytes: max_mb * 1024 * 1024,
+        }
+    }
+
+    fn get(&mut self, path: &str) -> Option<Input> {
+        let (compressed, _) = self.tracks.get(path)?;
+        let input = compressed.new_handle().into();
+
+        if let Some(index) = self.order.iter().position(|cached| cached == path) {
+            if let Some(cached) = self.order.remove(index) {
+                self.order.push_back(cached);
+            }
+        }
+        Some(input)
+    }
+
+    fn has_room(&self, bytes: usize) -> bool {
+        self.bytes + bytes <= self.max_bytes
+    }
+
+    // Returns false if the track is too big to cache at all
+    fn insert(&mut self, path: String, compressed: Compressed, bytes: usize) -> bool {
+        if bytes > self.max_bytes {
+            return false;
+        }
+        if self.tracks.contains_key(&path) {
+            return true;
+        }
+
+        while !self.has_room(bytes) {
+            match self.order.pop_front() {
+                Some(evicted) => {
+                    if let Some((_, evicted_bytes)) = self.tracks.remove(&evicted) {
+                        self.bytes -= evicted_bytes;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        self.bytes += bytes;
+        self.order.push_back(path.clone());
+        self.tracks.insert(path, (compressed, bytes));
+        true
+    }
+}
+
+// Decodes and compresses a whole track into memory, returning it with its size in bytes
+async fn load(path: &str) -> Result<(Compressed, usize), String> {
+    let source = songbird::ffmpeg(path)
+        .await
+        .map_err(|why| format!("Could not start ffmpeg for {}: {:?}", path, why))?;
+    let compressed = Compressed::new(source, Bitrate::BitsPerSecond(BITRATE))
+        .map_err(|why| format!("Could not compress {}: {:?}", path, why))?;
+
+    let loader = compressed.raw.spawn_loader();
+    let bytes = tokio::task::spawn_blocking(move || loader.join())
+        .await
+        .map_err(|why| format!("Could not wait for {} to load: {:?}", path, why))?
+        .map_err(|_| format!("Loading {} panicked", path))?
+        .map_err(|why| format!("Could not decode {}: {:?}", path, why))?;
+
+    Ok((compressed, bytes))
+}
+
+// An input for the track at `path`, from the cache if it's there. On a miss the track is played with ffmpeg like
+// before, and cached in the background for next time.
+pub async fn input(cache: &SharedTrackCache, path: &str) -> Result<Input, String> {
+    if let Some(input) = cache.lock().await.get(path) {
+        return Ok(input);
+    }
+
+    let cache = cache.clone();
+    let owned_path = path.to_string();
+    tokio::spawn(async move {
+        match load(&owned_path).await {
+            Ok((compressed, bytes)) => {
+                cache.lock().await.insert(owned_path, compressed, bytes);
+            }
+            Err(why) => println!("Could not cache track: {}", why),
+        }
+    });
+
+    songbird::ffmpeg(path)
+        .await
+        .map_err(|why| format!("Err starting source: {:?}", why))
+}
+
+// Caches every voicepack's tracks until the cache is full, and reports how much memory they take up
+pub async fn preload(cache: SharedTrackCache) {
+    let started = Instant::now();
+    let pwd = env::current_dir().expect("Could not get pwd.");
+    let pwd = pwd.display();
+
+    let voicepacks = match fs::read_dir(format!("{}/voicepacks", pwd)) {
+        Ok(ls) => ls,
+        Err(why) => {
+            println!("Could not read the voicepacks dir to preload: {:?}", why);
+            return;
+        }
+    };
+
+    let mut loaded = 0;
+    let mut full = false;
+    'voicepacks: for voicepack in voicepacks.flatten() {
+        let voicepack_name = voicepack.file_name();
+        if voicepack_name == "TEMPLATE" || !voicepack.path().is_dir() {
+            continue;
+        }
+
+        let tracks = match fs::read_dir(voicepack.path().join("tracks")) {
+            Ok(tracks) => tracks,
+            Err(_) => continue,
+        };
+        for track in tracks.flatten() {
+            let path = track.path().display().to_string();
+            let (compressed, bytes) = match load(&path).await {
+                Ok(loaded) => loaded,
+                Err(why) => {
+                    println!("Could not preload track: {}", why);
+                    continue;
+                }
+            };
+
+            let mut cache = cache.lock().await;
+            // Don't evict what we've already preloaded
+            if !cache.has_room(bytes) {
+                full = true;
+                break 'voicepacks;
+            }
+            cache.insert(path, compressed, bytes);
+            loaded += 1;
+        }
+    }
+
+    let cache = cache.lock().await;
+    println!(
+        "Preloaded {} tracks in {:.1}s, using {:.1} of {} MB{}",
+        loaded,
+        started.elapsed().as_secs_f32(),
+        cache.bytes as f32 / (1024.0 * 1024.0),
+        cache.max_bytes / (1024 * 1024),
+        if full {
+            " (full, the rest will be cached as they're played)"
+        } else {
+            ""
+        }
+    );
+}
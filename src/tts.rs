@@ -0,0 +1,181 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A line that takes longer than this to synthesize is given up on (and the TTS process killed), since the session
+// waits for it before handling its next event
+const TIMEOUT_SECS: u64 = 10;
+
+// Numbers each synthesis's partial file, so two sessions speaking the same line don't write to the same file
+static PARTIAL_FILES: AtomicU64 = AtomicU64::new(0);
+
+// TTS is only used if the bot is given a command to run, e.g.
+//   TTS_COMMAND="espeak-ng --stdin -w {output}"
+//   TTS_COMMAND="piper --model en_US-lessac-medium.onnx --output_file {output}"
+// The command is given the text on stdin, and should write a WAV file to `{output}`.
+pub fn is_enabled() -> bool {
+    env::var("TTS_COMMAND").is_ok()
+}
+
+// Fills in a template's `{placeholder}`s, or returns None if it uses one we don't have a value for (or one that's never
+// closed). `{{` and `}}` are a literal `{` and `}`.
+pub fn render(template: &str, values: &HashMap<&str, String>) -> Option<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(index) = rest.find(|c| c == '{' || c == '}') {
+        rendered.push_str(&rest[..index]);
+        let brace = &rest[index..index + 1];
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            rendered.push_str(brace);
+            rest = after;
+        } else if brace == "{" {
+            let end = rest.find('}')?;
+            rendered.push_str(values.get(&rest[..end])?);
+            rest = &rest[end + 1..];
+        } else {
+            // A lone `}` can't be mistaken for anything else
+            rendered.push_str(brace);
+        }
+    }
+    rendered.push_str(rest);
+    Some(rendered)
+}
+
+// Speaks `text` with TTS_COMMAND, returning the path of the WAV file. Lines are kept in a temp dir, so a line that
+// comes up again (e.g. the same victim and weapon) isn't synthesized twice.
+// This blocks on the TTS process (for up to TIMEOUT_SECS), so call it from `spawn_blocking`.
+pub fn synthesize(text: &str) -> Result<String, String> {
+    let command = env::var("TTS_COMMAND").map_err(|_| "TTS_COMMAND is not set".to_string())?;
+
+    let dir = env::temp_dir().join("blurber_tts");
+    fs::create_dir_all(&dir).map_err(|why| format!("Could not create TTS dir: {}", why))?;
+
+    let mut hasher = DefaultHasher::new();
+    command.hash(&mut hasher);
+    text.hash(&mut hasher);
+    let name = format!("{:x}", hasher.finish());
+    let output = dir.join(format!("{}.wav", name)).display().to_string();
+    if Path::new(&output).exists() {
+        return Ok(output);
+    }
+
+    // Written under another name first, so an interrupted run doesn't leave a broken file behind to be reused
+    let partial_number = PARTIAL_FILES.fetch_add(1, Ordering::Relaxed);
+    let partial = dir
+        .join(format!(
+            "{}.{}.{}.partial.wav",
+            name,
+            std::process::id(),
+            partial_number
+        ))
+        .display()
+        .to_string();
+    let mut args = command
+        .split_whitespace()
+        .map(|arg| arg.replace("{output}", &partial));
+    let program = args
+        .next()
+        .ok_or_else(|| "TTS_COMMAND is empty".to_string())?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|why| format!("Could not run TTS_COMMAND: {}", why))?;
+    // Dropping stdin closes it, so the command knows it has all of the text
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(text.as_bytes()),
+        None => Ok(()),
+    };
+    if let Err(why) = written {
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = fs::remove_file(&partial);
+        return Err(format!("Could not write text to TTS_COMMAND: {}", why));
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                let _ = fs::remove_file(&partial);
+                return Err(format!(
+                    "TTS_COMMAND took longer than {} seconds",
+                    TIMEOUT_SECS
+                ));
+            }
+            Err(why) => return Err(format!("TTS_COMMAND failed: {}", why)),
+        }
+    };
+    if !status.success() {
+        let _ = fs::remove_file(&partial);
+        return Err(format!("TTS_COMMAND exited with {}", status));
+    }
+
+    fs::rename(&partial, &output)
+        .map_err(|why| format!("Could not move TTS output into place: {}", why))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> HashMap<&'static str, String> {
+        HashMap::from([
+            ("character", "Wrel".to_string()),
+            ("victim", "xXSniperXx".to_string()),
+        ])
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        assert_eq!(
+            render("{victim} got headshot by {character}", &values()),
+            Some("xXSniperXx got headshot by Wrel".to_string())
+        );
+        assert_eq!(
+            render("no placeholders", &values()),
+            Some("no placeholders".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_value_skips_the_line() {
+        assert_eq!(render("{attacker} knifed {character}", &values()), None);
+        assert_eq!(render("{character} with the {weapon}!", &values()), None);
+    }
+
+    #[test]
+    fn unclosed_or_empty_placeholder_skips_the_line() {
+        assert_eq!(render("{character", &values()), None);
+        assert_eq!(render("{} got {victim}", &values()), None);
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(
+            render("{{{character}}} is {{AFK}}", &values()),
+            Some("{Wrel} is {AFK}".to_string())
+        );
+        assert_eq!(
+            render(":} {character}", &values()),
+            Some(":} Wrel".to_string())
+        );
+    }
+}
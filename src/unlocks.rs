@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use auraxis::realtime::event::Event;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+
+use crate::census::{self, Character};
+
+// The Census can take a little while to catch up with the ESS, so it's asked a few times before giving up
+const CHECK_ATTEMPTS: u32 = 3;
+const CHECK_INTERVAL_SECS: u64 = 10;
+
+// Something the tracked character unlocked, found in the Census after a `BattleRankUp`, `AchievementEarned` or
+// `SkillAdded` event. The auraxis branch we're pinned to doesn't parse those events' payloads, so they don't say
+// whose they are - every session gets them and checks whether it was its character.
+#[derive(Debug)]
+pub enum Unlock {
+    BattleRank {
+        battle_rank: u64,
+        prestige_level: u64,
+    },
+    // When the latest achievement or skill was unlocked
+    Achievement(DateTime<Utc>),
+    Skill(DateTime<Utc>),
+}
+
+pub type Unlocks = mpsc::UnboundedSender<Unlock>;
+
+// Checks the Census for whatever `event` could have been for `character`: a battle rank (or ASP rank) above theirs, or
+// an achievement or skill unlocked after `since`. Anything found is sent to `unlocks`.
+pub async fn check(event: Event, character: Character, since: DateTime<Utc>, unlocks: Unlocks) {
+    for attempt in 0..CHECK_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+        }
+
+        let found = match event {
+            Event::BattleRankUp => census::get_character_by_id(character.character_id)
+                .await
+                .map(|current| {
+                    let rank = (current.prestige_level, current.battle_rank);
+                    (rank > (character.prestige_level, character.battle_rank)).then(|| {
+                        Unlock::BattleRank {
+                            battle_rank: current.battle_rank,
+                            prestige_level: current.prestige_level,
+                        }
+                    })
+                }),
+            Event::AchievementEarned => {
+                census::get_latest_achievement(character.character_id, since)
+                    .await
+                    .map(|finished| finished.map(Unlock::Achievement))
+            }
+            Event::SkillAdded => census::get_latest_skill(character.character_id, since)
+                .await
+                .map(|unlocked| unlocked.map(Unlock::Skill)),
+            _ => return,
+        };
+
+        match found {
+            Ok(Some(unlock)) => {
+                let _ = unlocks.send(unlock);
+                return;
+            }
+            Ok(None) => {}
+            Err(why) => println!("Could not check {} for unlocks: {}", character.name, why),
+        }
+    }
+}